@@ -53,11 +53,13 @@ fn main() {
             std::fs::remove_dir_all(format!("{out_dir}/wrapper")).unwrap();
         }
         std::fs::create_dir_all(format!("{out_dir}/wrapper")).unwrap();
-        std::os::unix::fs::symlink(
-            "../../../../cached-nix-shell",
-            format!("{out_dir}/wrapper/nix-shell"),
-        )
-        .unwrap();
+        for name in ["nix-shell", "nix"] {
+            std::os::unix::fs::symlink(
+                "../../../../cached-nix-shell",
+                format!("{out_dir}/wrapper/{name}"),
+            )
+            .unwrap();
+        }
         println!("cargo:rustc-env=CNS_WRAP_PATH={out_dir}/wrapper");
 
         // Use nix and nix-shell from $PATH at runtime.
@@ -82,6 +82,10 @@ check builtins.readFile \
 	"f$PWD/test-tmp/test.nix" \
 	"$(b3sum ./test-tmp/test.nix | head -c 32)"
 
+check builtins.readFile-meta \
+	"m$PWD/test-tmp/test.nix" \
+	"$(stat -c '%s:%.9Y:%.9Z:%i:%d' ./test-tmp/test.nix)"
+
 run 'builtins.readFile "/nonexistent/readFile"'
 check builtins.readFile-ne \
 	"f/nonexistent/readFile" "-"
@@ -100,6 +104,10 @@ run 'builtins.readDir ./test-tmp'
 check builtins.readDir \
 	"d$PWD/test-tmp" "$(dir_b3sum ./test-tmp)"
 
+check builtins.readDir-meta \
+	"m$PWD/test-tmp" \
+	"$(stat -c '%s:%.9Y:%.9Z:%i:%d' ./test-tmp)"
+
 run 'builtins.readDir "/nonexistent/readDir"'
 check builtins.readDir-ne \
 	"d/nonexistent/readDir" "-"
@@ -109,6 +117,18 @@ run 'builtins.readDir ./test-tmp/many-dirs'
 check builtins.readDir-many-dirs \
 	"d$PWD/test-tmp/many-dirs" "$(dir_b3sum ./test-tmp/many-dirs)"
 
+unset TRACE_NIX_TEST_VAR
+run 'builtins.getEnv "TRACE_NIX_TEST_VAR"'
+check builtins.getEnv-ne \
+	"eTRACE_NIX_TEST_VAR" "-"
+
+export TRACE_NIX_TEST_VAR="a b"
+run 'builtins.getEnv "TRACE_NIX_TEST_VAR"'
+check builtins.getEnv \
+	"eTRACE_NIX_TEST_VAR" "=a b"
+unset TRACE_NIX_TEST_VAR
+
+
 run_without_p
 check implicit:shell.nix \
 	"s$PWD/shell.nix" "-"
@@ -27,6 +27,14 @@ static const char *pwd = NULL;
 
 #define LEN 16
 
+#ifdef __APPLE__
+#define ST_MTIM st_mtimespec
+#define ST_CTIM st_ctimespec
+#else
+#define ST_MTIM st_mtim
+#define ST_CTIM st_ctim
+#endif
+
 // Locks
 
 #ifdef __APPLE__
@@ -54,8 +62,10 @@ static pthread_mutex_t buf_mutex = PTHREAD_MUTEX_INITIALIZER;
 static void convert_digest(char [static LEN*2+1], const uint8_t [static LEN]);
 static int enable(const char *);
 static void hash_dir(char [static LEN*2+1], DIR *);
-static void hash_file(char [static LEN*2+1], int);
+static void hash_file(char [static LEN*2+1], int, struct stat *);
+static void print_env(const char *, const char *);
 static void print_log(char, const char *, const char *);
+static void print_meta(const char *, const struct stat *);
 static void print_stat(int result, const char *path, struct stat *sb);
 static int strcmp_qsort(const void *, const void *);
 
@@ -75,6 +85,11 @@ static void __attribute__((constructor)) init() {
 			fprintf(stderr, "trace-nix: can't open file %s: %s\n", fname,
 				strerror(errno));
 			errno = 0;
+		} else {
+			// Format header, see README.md.  Flushed right away, so a
+			// forked child that exits without exec doesn't write it again.
+			fprintf(log_f, "cached-nix-shell-trace%c2%c", (char)0, (char)0);
+			fflush(log_f);
 		}
 #ifdef __APPLE__
 		pwd = getcwd(NULL, 0);
@@ -136,8 +151,10 @@ WRAPPER(int, open, (const char *path, int flags, ...)) {
 			print_log('f', path, "-");
 		} else {
 			char digest[LEN*2+1];
-			hash_file(digest, fd);
+			struct stat sb;
+			hash_file(digest, fd, &sb);
 			print_log('f', path, digest);
+			print_meta(path, &sb);
 		}
 	}
 
@@ -153,11 +170,20 @@ WRAPPER(DIR *, opendir, (const char *path)) {
 			char digest[LEN*2+1];
 			hash_dir(digest, dirp);
 			print_log('d', path, digest);
+			struct stat sb;
+			if (fstat(dirfd(dirp), &sb) == 0)
+				print_meta(path, &sb);
 		}
 	}
 	return dirp;
 }
 
+WRAPPER(char *, getenv, (const char *name)) {
+	char *result = REAL(getenv)(name);
+	print_env(name, result);
+	return result;
+}
+
 ////////////////////////////////////////////////////////////////////////////////
 
 static int enable(const char *path) {
@@ -231,14 +257,42 @@ static void print_log(char op, const char *path, const char *result) {
 	UNLOCK(print_mutex);
 }
 
-static void hash_file(char digest_s[static LEN*2+1], int fd) {
-	struct stat stat_;
-	int rc = fstat(fd, &stat_);
+static void print_env(const char *name, const char *value) {
+	// Don't log lookups made by the logging itself.
+	static __thread int busy = 0;
+	if (log_f == NULL || busy)
+		return;
+	busy = 1;
+	LOCK(print_mutex);
+	fprintf(
+		log_f,
+		"e" "%s%c" "%s%s%c",
+		name, (char)0,
+		value == NULL ? "-" : "=", value == NULL ? "" : value, (char)0
+	);
+	fflush(log_f);
+	UNLOCK(print_mutex);
+	busy = 0;
+}
+
+static void print_meta(const char *path, const struct stat *sb) {
+	char buf[128];
+	snprintf(buf, sizeof buf, "%llu:%lld.%09ld:%lld.%09ld:%llu:%llu",
+		(unsigned long long)sb->st_size,
+		(long long)sb->ST_MTIM.tv_sec, (long)sb->ST_MTIM.tv_nsec,
+		(long long)sb->ST_CTIM.tv_sec, (long)sb->ST_CTIM.tv_nsec,
+		(unsigned long long)sb->st_ino,
+		(unsigned long long)sb->st_dev);
+	print_log('m', path, buf);
+}
+
+static void hash_file(char digest_s[static LEN*2+1], int fd, struct stat *sb) {
+	int rc = fstat(fd, sb);
 	if (rc != 0)
 		FATAL();
 	char *mmaped = NULL;
-	if (stat_.st_size != 0) {
-		mmaped = mmap(NULL, stat_.st_size, PROT_READ, MAP_PRIVATE, fd, 0);
+	if (sb->st_size != 0) {
+		mmaped = mmap(NULL, sb->st_size, PROT_READ, MAP_PRIVATE, fd, 0);
 		if (mmaped == MAP_FAILED) {
 			strcpy(digest_s, "e");
 			return;
@@ -247,13 +301,13 @@ static void hash_file(char digest_s[static LEN*2+1], int fd) {
 
 	blake3_hasher hasher;
 	blake3_hasher_init(&hasher);
-	blake3_hasher_update(&hasher, mmaped, stat_.st_size);
+	blake3_hasher_update(&hasher, mmaped, sb->st_size);
 	uint8_t digest_b[LEN];
 	blake3_hasher_finalize(&hasher, digest_b, LEN);
 	convert_digest(digest_s, digest_b);
 
-	if (stat_.st_size != 0) {
-		rc = munmap(mmaped, stat_.st_size);
+	if (sb->st_size != 0) {
+		rc = munmap(mmaped, sb->st_size);
 		if (rc != 0)
 			FATAL();
 	}
@@ -1,2 +1,3 @@
 [ "$IN_NIX_SHELL" = impure ] && [ -n "$PS1" ] && [ -e ~/.bashrc ] && source ~/.bashrc
+if [ -n "${__CNS_STATE-}" ]; then source "$__CNS_STATE"; unset __CNS_STATE; fi
 [ -n "$PS1" -a -z "$NIX_SHELL_PRESERVE_PROMPT" ] && PS1='\n\[\033[1;32m\][cached-nix-shell:\w]\$\[\033[0m\] '
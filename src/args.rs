@@ -10,12 +10,17 @@
 //! compatible way, so it is appropriate to code this explicitly rather than use
 //! such libraries.
 
+use crate::dev_env::Backend;
+use crate::export::Format;
+use crate::gc::parse_duration;
+use crate::nix_version;
+use crate::output::Mode;
 use std::collections::VecDeque;
 use std::ffi::{OsStr, OsString};
 use std::io::Write;
 use std::os::unix::ffi::OsStrExt;
-use std::os::unix::process::CommandExt;
 use std::process::{exit, Command};
+use std::time::Duration;
 use ufcs::Pipe;
 
 pub enum RunMode {
@@ -25,6 +30,8 @@ pub enum RunMode {
     Shell(OsString),
     /// --exec CMD ARGS...
     Exec(OsString, Vec<OsString>),
+    /// --export FORMAT (not in shebang)
+    Export(Format),
 }
 
 pub struct Args {
@@ -46,16 +53,28 @@ pub struct Args {
     pub other_kw: Vec<OsString>,
     /// weak keyword arguments
     pub weak_kw: Vec<OsString>,
+    /// --explain (not in shebang)
+    pub explain: bool,
+    /// --allow-stale (not in shebang)
+    pub allow_stale: bool,
+    /// --rerun-shell-hook (not in shebang)
+    pub rerun_shell_hook: bool,
+    /// --eval-output MODE (not in shebang)
+    pub eval_output: Option<Mode>,
+    /// --backend BACKEND (not in shebang)
+    pub backend: Option<Backend>,
+    /// --ttl DURATION (not in shebang)
+    pub ttl: Option<Duration>,
 }
 
-struct NixShellOption {
+pub struct NixShellOption {
     /// true if adding or removing this option should not invalidate the cache
-    is_weak: bool,
-    arg_count: u8,
-    names: &'static [&'static str],
+    pub is_weak: bool,
+    pub arg_count: u8,
+    pub names: &'static [&'static str],
 }
 
-const fn opt(
+pub const fn opt(
     is_weak: bool,
     arg_count: u8,
     names: &'static [&'static str],
@@ -87,12 +106,9 @@ const OPTIONS_DB: &[NixShellOption] = &[
     opt(true, 2, &["--option"]),
 ];
 
-impl Args {
-    pub fn parse(
-        args: Vec<OsString>,
-        in_shebang: bool,
-    ) -> Result<Args, String> {
-        let mut res = Args {
+impl Default for Args {
+    fn default() -> Args {
+        Args {
             packages_or_expr: false,
             pure: false,
             include_nix_path: Vec::new(),
@@ -102,7 +118,22 @@ impl Args {
             rest: Vec::new(),
             other_kw: Vec::new(),
             weak_kw: Vec::new(),
-        };
+            explain: false,
+            allow_stale: false,
+            rerun_shell_hook: false,
+            eval_output: None,
+            backend: None,
+            ttl: None,
+        }
+    }
+}
+
+impl Args {
+    pub fn parse(
+        args: Vec<OsString>,
+        in_shebang: bool,
+    ) -> Result<Args, String> {
+        let mut res = Args::default();
         let mut it = VecDeque::<OsString>::from(args);
         while let Some(arg) = get_next_arg(&mut it) {
             let mut next = || -> Result<OsString, String> {
@@ -148,6 +179,22 @@ impl Args {
             } else if arg == "--exec" && !in_shebang {
                 res.run = RunMode::Exec(next()?, it.into());
                 break;
+            } else if arg == "--explain" && !in_shebang {
+                res.explain = true;
+            } else if arg == "--allow-stale" && !in_shebang {
+                res.allow_stale = true;
+            } else if arg == "--rerun-shell-hook" && !in_shebang {
+                res.rerun_shell_hook = true;
+            } else if arg == "--eval-output" && !in_shebang {
+                res.eval_output =
+                    Some(Mode::parse(&next()?.to_string_lossy())?);
+            } else if arg == "--export" && !in_shebang {
+                res.run =
+                    RunMode::Export(Format::parse(&next()?.to_string_lossy())?);
+            } else if arg == "--backend" && !in_shebang {
+                res.backend = Some(Backend::parse(&next()?.to_string_lossy())?);
+            } else if arg == "--ttl" && !in_shebang {
+                res.ttl = Some(parse_duration(&next()?.to_string_lossy())?);
             } else if arg == "--keep" {
                 res.keep.push(next()?);
             } else if arg == "--version" {
@@ -164,7 +211,7 @@ impl Args {
     }
 }
 
-fn get_next_arg(it: &mut VecDeque<OsString>) -> Option<OsString> {
+pub fn get_next_arg(it: &mut VecDeque<OsString>) -> Option<OsString> {
     let arg = it.pop_front()?;
     let argb = arg.as_bytes();
     if argb.len() > 2 && argb[0] == b'-' && is_alpha(argb[1]) {
@@ -209,10 +256,24 @@ fn exit_version() {
         println!("Using {}nix-shell", env!("CNS_NIX"));
     }
     std::io::stdout().flush().unwrap();
-    Command::new(concat!(env!("CNS_NIX"), "nix-shell"))
+    let _ = Command::new(concat!(env!("CNS_NIX"), "nix-shell"))
         .arg("--version")
-        .exec();
-    exit(1);
+        .status();
+    let nix = nix_version::get();
+    match nix.version {
+        Some(version) => println!(
+            "Nix {version} ({}), experimental features: {}",
+            nix.path.display(),
+            match nix.features() {
+                features if features.is_empty() => "none".to_string(),
+                features => features.join(" "),
+            }
+        ),
+        None => {
+            println!("Can't determine the version of {}", nix.path.display())
+        }
+    }
+    exit(0);
 }
 
 #[cfg(test)]
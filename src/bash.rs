@@ -1,3 +1,42 @@
+use nix::fcntl::{fcntl, FcntlArg, FdFlag};
+use std::ffi::OsString;
+use std::fs::File;
+use std::io::{Seek, Write};
+use std::os::unix::io::AsRawFd;
+
+/// Print non-exported variables and functions of the current shell as a
+/// script to be sourced later.  Special bash variables, exported variables
+/// (captured by `env -0`) and readonly variables are skipped.
+pub const DUMP_SHELL: &[u8] = br#"for __cns_var in $(compgen -v); do
+	case $__cns_var in
+		BASH*|COMP*|EPOCH*|HIST*|__cns_*|_|COLUMNS|DIRSTACK|EUID|FUNCNAME|\
+		GROUPS|HOSTNAME|HOSTTYPE|IFS|LINENO|LINES|MACHTYPE|MAILCHECK|\
+		OLDPWD|OPTERR|OPTIND|OSTYPE|PIPESTATUS|PPID|PS1|PS2|PS4|PWD|\
+		RANDOM|SECONDS|SHELLOPTS|SHLVL|SRANDOM|UID) continue;;
+	esac
+	case ${!__cns_var@a} in *[rx]*) continue;; esac
+	declare -p "$__cns_var"
+done
+declare -f"#;
+
+/// Write `state` (see [`DUMP_SHELL`]) followed by `epilogue` into an anonymous
+/// file that a child bash process can source as `/dev/fd/N`.  The file should
+/// be kept open until exec.  The script closes the descriptor itself, so it
+/// doesn't leak into the user's shell.
+pub fn state_file(
+    state: &[u8],
+    epilogue: &[u8],
+) -> Result<(File, OsString), std::io::Error> {
+    let mut file = tempfile::tempfile()?;
+    let fd = file.as_raw_fd();
+    fcntl(fd, FcntlArg::F_SETFD(FdFlag::empty()))?;
+    file.write_all(state)?;
+    write!(file, "\nexec {fd}<&-\n")?;
+    file.write_all(epilogue)?;
+    file.rewind()?;
+    Ok((file, OsString::from(format!("/dev/fd/{fd}"))))
+}
+
 pub fn is_literal_bash_string(command: &[u8]) -> bool {
     let mut previous = None;
     for &c in command {
@@ -17,6 +56,17 @@ pub fn is_literal_bash_string(command: &[u8]) -> bool {
     true
 }
 
+/// True if `name` is a valid name of a shell variable.
+pub fn is_identifier(name: &[u8]) -> bool {
+    match name.split_first() {
+        Some((first, rest)) => {
+            (first.is_ascii_alphabetic() || *first == b'_')
+                && rest.iter().all(|c| c.is_ascii_alphanumeric() || *c == b'_')
+        }
+        None => false,
+    }
+}
+
 pub fn quote(arg: &[u8]) -> Vec<u8> {
     let mut result = vec![b'\''];
     for &i in arg {
@@ -0,0 +1,446 @@
+//! Cache entries
+//!
+//! Each entry is stored in a single file `{hash}.entry`, where `hash` is the
+//! hash of the inputs.  The file is written to a temporary file first and then
+//! renamed into place, so readers never see a partially written entry.
+//!
+//! File format:
+//! ```text
+//! "cached-nix-shell-entry" NUL VERSION NUL CHECKSUM NUL PAYLOAD
+//! ```
+//! `CHECKSUM` is the blake3 hash of `PAYLOAD`.  `PAYLOAD` is a list of named
+//! sections encoded by [`serialize_vecs`]: `name1, data1, name2, data2, ...`.
+//! Unknown sections are ignored, but all known ones are required, so adding a
+//! section requires bumping `VERSION`.  Entries with a different `VERSION`, a
+//! wrong checksum or missing sections are treated as absent.
+
+use crate::output::{self, Output};
+use crate::trace::{self, Trace};
+use crate::{
+    deserealize_env, deserialize_args, deserialize_vecs, serialize_args,
+    serialize_env, serialize_vecs, EnvMap, NixShellInput,
+};
+use once_cell::sync::Lazy;
+use std::collections::BTreeMap;
+use std::ffi::OsString;
+use std::fs::File;
+use std::io::{ErrorKind, Write};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tempfile::NamedTempFile;
+use ufcs::Pipe;
+
+static DIR: Lazy<PathBuf> = Lazy::new(|| {
+    crate::config::GLOBAL
+        .cache_dir
+        .clone()
+        .unwrap_or_else(|| crate::XDG_DIRS.get_cache_home())
+});
+
+const MAGIC: &[u8] = b"cached-nix-shell-entry";
+const VERSION: &[u8] = b"1";
+
+/// Default time-to-live of entries that depend on the network, the same as
+/// the default `tarball-ttl` of Nix.
+pub const TTL: Duration = Duration::from_secs(3600);
+
+pub struct Entry {
+    /// Serialized `NixShellInput` (the data the hash is computed from).
+    pub inputs: Vec<u8>,
+    pub env: EnvMap,
+    /// Enabled `shopt` options.
+    pub bashopts: Vec<OsString>,
+    /// Enabled `set -o` options.
+    pub shellopts: Vec<OsString>,
+    /// Non-exported shell variables and functions as a bash script.
+    pub shell: Vec<u8>,
+    /// The value of `shellHook` during the evaluation.
+    pub shell_hook: Vec<u8>,
+    /// Output of `nix-shell` during the evaluation.
+    pub output: Output,
+    pub trace: Trace,
+    /// Path to the .drv file (or to the built environment for `--develop`),
+    /// empty if it's unknown.
+    pub drv: String,
+    /// When the entry was evaluated.
+    pub created: SystemTime,
+    /// How long the evaluation took.
+    pub eval_duration: Duration,
+    /// Whether the entry depends on the network, and thus stays valid only for
+    /// the TTL after `created`.
+    pub network: bool,
+}
+
+impl Entry {
+    /// Load an entry.  Return `None` if it's absent, corrupt or was written
+    /// by an incompatible version of cached-nix-shell.
+    pub fn load(hash: &str) -> Option<Entry> {
+        let fname = entry_path(hash)?;
+        match Entry::read(&fname) {
+            Ok(entry) => Some(entry),
+            Err(LoadError::NotFound) | Err(LoadError::Version) => None,
+            Err(LoadError::Io(e)) => {
+                eprintln!("cached-nix-shell: can't read {fname:?}: {e}");
+                None
+            }
+            Err(LoadError::Corrupt) => {
+                eprintln!(
+                    "cached-nix-shell: ignoring corrupt cache entry {fname:?}"
+                );
+                None
+            }
+        }
+    }
+
+    /// Load an entry from the specified file.
+    pub fn read(fname: &Path) -> Result<Entry, LoadError> {
+        match std::fs::read(fname) {
+            Ok(data) => Entry::parse(&data),
+            Err(e) if e.kind() == ErrorKind::NotFound => {
+                Err(LoadError::NotFound)
+            }
+            Err(e) => Err(LoadError::Io(e)),
+        }
+    }
+
+    /// Update the modification time of the entry file.  It is used as the
+    /// last-use time by `--gc`.
+    pub fn touch(hash: &str) {
+        if let Some(fname) = entry_path(hash) {
+            let _ = File::open(fname)
+                .and_then(|file| file.set_modified(SystemTime::now()));
+        }
+    }
+
+    /// When the entry expires with the given TTL, if it depends on the
+    /// network.
+    pub fn expires(&self, ttl: Duration) -> Option<SystemTime> {
+        Some(self.created + ttl).filter(|_| self.network)
+    }
+
+    /// True if the entry has outlived the TTL.
+    pub fn expired(&self, ttl: Duration) -> bool {
+        self.expires(ttl).is_some_and(|t| t <= SystemTime::now())
+    }
+
+    /// Decode [`Entry::inputs`].
+    pub fn decode_inputs(&self) -> Option<NixShellInput> {
+        NixShellInput::deserialize(&self.inputs)
+    }
+
+    /// Atomically store the entry.
+    pub fn store(&self, hash: &str) -> Result<(), std::io::Error> {
+        let fname = place_file(format!("{hash}.entry"))?;
+        let mut file = NamedTempFile::new_in(fname.parent().unwrap())?;
+        file.write_all(&self.serialize())?;
+        file.as_file().sync_all()?;
+        file.persist(fname)?;
+        Ok(())
+    }
+
+    fn serialize(&self) -> Vec<u8> {
+        let env = serialize_env(&self.env);
+        let bashopts = serialize_args(&self.bashopts);
+        let shellopts = serialize_args(&self.shellopts);
+        let output = output::serialize(&self.output);
+        let trace = self.trace.serialize();
+        let created = self
+            .created
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs()
+            .to_string();
+        let eval_duration = self.eval_duration.as_millis().to_string();
+
+        let sections: &[&[u8]] = &[
+            b"inputs",
+            &self.inputs,
+            b"env",
+            &env,
+            b"bashopts",
+            &bashopts,
+            b"shellopts",
+            &shellopts,
+            b"shell",
+            &self.shell,
+            b"shell_hook",
+            &self.shell_hook,
+            b"output",
+            &output,
+            b"trace",
+            &trace,
+            b"drv",
+            self.drv.as_bytes(),
+            b"created",
+            created.as_bytes(),
+            b"eval_duration",
+            eval_duration.as_bytes(),
+            b"network",
+            if self.network { b"1" } else { b"0" },
+        ];
+        let payload = serialize_vecs(sections);
+        [
+            MAGIC,
+            b"\0",
+            VERSION,
+            b"\0",
+            blake3::hash(&payload).to_hex().as_bytes(),
+            b"\0",
+            &payload,
+        ]
+        .concat()
+    }
+
+    fn parse(data: &[u8]) -> Result<Entry, LoadError> {
+        let mut header = data.splitn(4, |&b| b == 0);
+        if header.next() != Some(MAGIC) {
+            return Err(LoadError::Corrupt);
+        }
+        if header.next() != Some(VERSION) {
+            return Err(LoadError::Version);
+        }
+        let checksum = header.next().ok_or(LoadError::Corrupt)?;
+        let payload = header.next().ok_or(LoadError::Corrupt)?;
+        if blake3::hash(payload).to_hex().as_bytes() != checksum {
+            return Err(LoadError::Corrupt);
+        }
+
+        let vecs = deserialize_vecs(payload).ok_or(LoadError::Corrupt)?;
+        if vecs.len() % 2 != 0 {
+            return Err(LoadError::Corrupt);
+        }
+        let mut sections = vecs
+            .chunks(2)
+            .map(|kv| (kv[0], kv[1]))
+            .collect::<BTreeMap<_, _>>();
+        let mut section = |name: &[u8]| -> Result<Vec<u8>, LoadError> {
+            sections
+                .remove(name)
+                .map(Vec::from)
+                .ok_or(LoadError::Corrupt)
+        };
+        let mut number = |name: &[u8]| -> Result<u64, LoadError> {
+            String::from_utf8(section(name)?)
+                .ok()
+                .and_then(|x| x.parse().ok())
+                .ok_or(LoadError::Corrupt)
+        };
+        let created = UNIX_EPOCH + Duration::from_secs(number(b"created")?);
+        let eval_duration = Duration::from_millis(number(b"eval_duration")?);
+        let network = match number(b"network")? {
+            0 => false,
+            1 => true,
+            _ => return Err(LoadError::Corrupt),
+        };
+
+        Ok(Entry {
+            inputs: section(b"inputs")?,
+            env: section(b"env")?
+                .pipe(deserealize_env)
+                .ok_or(LoadError::Corrupt)?,
+            bashopts: deserialize_args(&section(b"bashopts")?),
+            shellopts: deserialize_args(&section(b"shellopts")?),
+            shell: section(b"shell")?,
+            shell_hook: section(b"shell_hook")?,
+            output: output::deserialize(&section(b"output")?)
+                .ok_or(LoadError::Corrupt)?,
+            trace: Trace::load(section(b"trace")?)?,
+            drv: String::from_utf8(section(b"drv")?)
+                .map_err(|_| LoadError::Corrupt)?,
+            created,
+            eval_duration,
+            network,
+        })
+    }
+}
+
+pub enum LoadError {
+    NotFound,
+    Io(std::io::Error),
+    /// Written by an incompatible version.
+    Version,
+    Corrupt,
+}
+
+impl std::fmt::Display for LoadError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            LoadError::NotFound => write!(f, "not found"),
+            LoadError::Io(e) => write!(f, "{e}"),
+            LoadError::Version => write!(f, "incompatible format version"),
+            LoadError::Corrupt => write!(f, "corrupt"),
+        }
+    }
+}
+
+impl From<trace::LoadError> for LoadError {
+    fn from(e: trace::LoadError) -> LoadError {
+        match e {
+            trace::LoadError::Version => LoadError::Version,
+            trace::LoadError::Corrupt => LoadError::Corrupt,
+        }
+    }
+}
+
+/// The cache directory: `dir` from the `[cache]` section of the global config,
+/// or `$XDG_CACHE_HOME/cached-nix-shell`.
+pub fn dir() -> &'static Path {
+    &DIR
+}
+
+/// Path to a file in the cache directory.  The directory is created if needed.
+pub fn place_file(name: impl AsRef<Path>) -> Result<PathBuf, std::io::Error> {
+    let fname = dir().join(name);
+    std::fs::create_dir_all(fname.parent().unwrap())?;
+    Ok(fname)
+}
+
+/// Path to a file in the cache directory, if it exists.
+pub fn find_file(name: impl AsRef<Path>) -> Option<PathBuf> {
+    Some(dir().join(name)).filter(|fname| fname.exists())
+}
+
+fn entry_path(hash: &str) -> Option<PathBuf> {
+    find_file(format!("{hash}.entry"))
+}
+
+/// Return hashes of all entries in the cache.  Other files named `*.entry`
+/// are skipped.
+pub fn list_hashes() -> Vec<String> {
+    let mut result = std::fs::read_dir(dir())
+        .into_iter()
+        .flatten()
+        .flatten()
+        .filter_map(|entry| {
+            let name = entry.file_name().into_string().ok()?;
+            let hash = name.strip_suffix(".entry")?;
+            is_hash(hash).then(|| hash.to_string())
+        })
+        .collect::<Vec<_>>();
+    result.sort();
+    result
+}
+
+/// True for hex-encoded blake3 hashes, as used in entry names.
+fn is_hash(s: &str) -> bool {
+    s.len() == 64 && s.bytes().all(|c| matches!(c, b'0'..=b'9' | b'a'..=b'f'))
+}
+
+/// Find the hash of an existing entry by its unique prefix.
+pub fn resolve_hash(prefix: &str) -> Result<String, String> {
+    let matches = list_hashes()
+        .into_iter()
+        .filter(|hash| hash.starts_with(prefix))
+        .collect::<Vec<_>>();
+    match &matches[..] {
+        [hash] => Ok(hash.clone()),
+        [] => Err(format!("no cache entry matches {prefix:?}")),
+        _ => Err(format!("ambiguous entry prefix {prefix:?}")),
+    }
+}
+
+/// Pinned entries are never evicted by `--gc`.
+pub fn is_pinned(hash: &str) -> bool {
+    find_file(format!("{hash}.pin")).is_some()
+}
+
+pub fn set_pinned(hash: &str, pinned: bool) -> Result<(), std::io::Error> {
+    let fname = place_file(format!("{hash}.pin"))?;
+    if pinned {
+        File::create(fname)?;
+    } else if let Err(e) = std::fs::remove_file(fname) {
+        if e.kind() != ErrorKind::NotFound {
+            return Err(e);
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::output::Stream;
+
+    fn sample() -> Entry {
+        let mut env = EnvMap::new();
+        env.insert(OsString::from("FOO"), OsString::from("bar=baz"));
+        env.insert(OsString::from("EMPTY"), OsString::new());
+        Entry {
+            inputs: b"some\0inputs".to_vec(),
+            env,
+            bashopts: vec!["nullglob".into()],
+            shellopts: vec!["pipefail".into(), "errexit".into()],
+            shell: b"foo() { :; }".to_vec(),
+            shell_hook: b"echo hello".to_vec(),
+            output: vec![(Stream::Stdout, b"hello\n".to_vec())],
+            trace: Trace::load(b"\0s/foo\0+\0f/bar\0-\0".to_vec()).unwrap(),
+            drv: "/nix/store/00000000000000000000000000000000-foo.drv".into(),
+            created: UNIX_EPOCH + Duration::from_secs(1_600_000_000),
+            eval_duration: Duration::from_millis(1234),
+            network: true,
+        }
+    }
+
+    #[test]
+    fn roundtrip() {
+        let data = sample().serialize();
+        let entry = Entry::parse(&data).ok().unwrap();
+        assert_eq!(entry.inputs, sample().inputs);
+        assert_eq!(entry.env, sample().env);
+        assert_eq!(entry.bashopts, sample().bashopts);
+        assert_eq!(entry.shellopts, sample().shellopts);
+        assert_eq!(entry.shell, sample().shell);
+        assert_eq!(entry.shell_hook, sample().shell_hook);
+        assert_eq!(entry.output, sample().output);
+        assert_eq!(entry.trace.serialize(), sample().trace.serialize());
+        assert_eq!(entry.drv, sample().drv);
+        assert_eq!(entry.created, sample().created);
+        assert_eq!(entry.eval_duration, sample().eval_duration);
+        assert_eq!(entry.network, sample().network);
+        assert!(entry.expired(TTL));
+    }
+
+    #[test]
+    fn test_is_hash() {
+        assert!(is_hash(blake3::hash(b"").to_hex().as_str()));
+        assert!(!is_hash("abc"));
+        assert!(!is_hash(&"A".repeat(64)));
+    }
+
+    #[test]
+    fn rejects_damaged() {
+        let data = sample().serialize();
+        for len in 0..data.len() {
+            assert!(Entry::parse(&data[..len]).is_err());
+        }
+        let mut flipped = data.clone();
+        *flipped.last_mut().unwrap() ^= 1;
+        assert!(matches!(Entry::parse(&flipped), Err(LoadError::Corrupt)));
+
+        let other_version =
+            [b"cached-nix-shell-entry\x000\0" as &[u8], &data].concat();
+        assert!(matches!(
+            Entry::parse(&other_version),
+            Err(LoadError::Version)
+        ));
+
+        // Every section is required.
+        let payload = serialize_vecs(&[b"inputs" as &[u8], b""]);
+        let partial = [
+            b"cached-nix-shell-entry\x001\0" as &[u8],
+            blake3::hash(&payload).to_hex().as_bytes(),
+            b"\0",
+            &payload,
+        ]
+        .concat();
+        assert!(matches!(Entry::parse(&partial), Err(LoadError::Corrupt)));
+    }
+
+    #[test]
+    fn vecs_roundtrip() {
+        let vecs: &[&[u8]] = &[b"", b"a\0b", b"123"];
+        assert_eq!(deserialize_vecs(&serialize_vecs(vecs)).unwrap(), vecs);
+        assert_eq!(deserialize_vecs(b"5\0abc"), None);
+        assert_eq!(deserialize_vecs(b"x\0"), None);
+    }
+}
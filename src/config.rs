@@ -0,0 +1,299 @@
+//! Configuration files
+//!
+//! The global config is read from `$XDG_CONFIG_HOME/cached-nix-shell/config.toml`.
+//! A project can add its own `cached-nix-shell.toml` next to `shell.nix` (or
+//! the shebang script), which is layered on top of the global config: lists
+//! are extended, other values are overridden.  Variables passed to the
+//! evaluation end up in cache entries, so only the global config can add them:
+//! otherwise a cloned repository could collect secrets from the environment.
+//!
+//! ```toml
+//! [env]
+//! pass = ["LOCALE_ARCHIVE"]    # passed to nix-shell, global config only
+//! keep = ["SSH_AUTH_SOCK"]     # same as `--keep`, global config only
+//! preserve = ["EDITOR"]        # always taken from the ambient environment
+//!
+//! [env.delimiters]             # merged with the ambient value in impure mode
+//! PKG_CONFIG_PATH = ":"
+//!
+//! [cache]
+//! dir = "/var/cache/cns"       # global config only
+//! allow-stale = true           # same as `--allow-stale`
+//! lock-timeout = "10m"
+//! gc-roots = true
+//! backend = "print-dev-env"    # same as `--backend print-dev-env`
+//! ttl = "1d"                   # same as `--ttl 1d`
+//!
+//! [shell]
+//! rerun-hook = true            # same as `--rerun-shell-hook`
+//! eval-output = "replay"       # same as `--eval-output replay`
+//!
+//! [gc]                         # defaults for `--gc`
+//! max-age = "30d"
+//! max-size = "2G"
+//! ```
+
+use crate::dev_env::Backend;
+use crate::gc::{parse_duration, parse_size};
+use crate::output::Mode;
+use once_cell::sync::Lazy;
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+use toml::value::{Table, Value};
+use ufcs::Pipe;
+
+/// Name of the project-level config file.
+pub const PROJECT_FILE: &str = "cached-nix-shell.toml";
+
+pub static GLOBAL: Lazy<Config> = Lazy::new(|| {
+    let mut config = Config::default();
+    if let Some(fname) = crate::XDG_DIRS.find_config_file("config.toml") {
+        config.load_file(&fname, true).pipe(crate::unwrap_or_errx);
+    }
+    config
+});
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Config {
+    /// Additional variables passed to `nix-shell --pure`.
+    pub pass: Vec<String>,
+    /// Default `--keep` variables.
+    pub keep: Vec<String>,
+    /// Additional variables always taken from the ambient environment.
+    pub preserve: Vec<String>,
+    /// Additional list variables merged with the ambient environment in
+    /// impure mode, and their delimiters.
+    pub delimiters: BTreeMap<String, String>,
+    pub cache_dir: Option<PathBuf>,
+    pub allow_stale: bool,
+    pub lock_timeout: Duration,
+    pub gc_roots: bool,
+    pub backend: Backend,
+    /// Time-to-live of entries that depend on the network.
+    pub ttl: Duration,
+    pub rerun_shell_hook: bool,
+    pub eval_output: Mode,
+    pub gc_max_age: Option<Duration>,
+    pub gc_max_size: Option<u64>,
+}
+
+impl Default for Config {
+    fn default() -> Config {
+        Config {
+            pass: Vec::new(),
+            keep: Vec::new(),
+            preserve: Vec::new(),
+            delimiters: BTreeMap::new(),
+            cache_dir: None,
+            allow_stale: false,
+            lock_timeout: crate::lock::LOCK_TIMEOUT,
+            gc_roots: true,
+            backend: Backend::Run,
+            ttl: crate::cache::TTL,
+            rerun_shell_hook: false,
+            eval_output: Mode::Show,
+            gc_max_age: None,
+            gc_max_size: None,
+        }
+    }
+}
+
+/// The global config with the project config in `dir` (if any) on top.
+pub fn load(dir: &Path) -> Config {
+    let mut config = GLOBAL.clone();
+    let fname = dir.join(PROJECT_FILE);
+    if fname.is_file() {
+        config.load_file(&fname, false).pipe(crate::unwrap_or_errx);
+    }
+    config
+}
+
+impl Config {
+    fn load_file(&mut self, fname: &Path, global: bool) -> Result<(), String> {
+        let text = std::fs::read_to_string(fname)
+            .map_err(|e| format!("can't read {fname:?}: {e}"))?;
+        self.apply(fname, &text, global)
+            .map_err(|e| format!("{}: {e}", fname.display()))
+    }
+
+    fn apply(
+        &mut self,
+        fname: &Path,
+        text: &str,
+        global: bool,
+    ) -> Result<(), String> {
+        let warn = |msg: String| {
+            eprintln!("cached-nix-shell: warning: {}: {msg}", fname.display())
+        };
+        let mut values = Vec::new();
+        let table = toml::from_str(text).map_err(|e| format!("{e}"))?;
+        flatten("", table, &mut values);
+        for (key, value) in values {
+            let err = |e: String| format!("{key}: {e}");
+            match key.as_str() {
+                "env.pass" | "env.keep" | "cache.dir" if !global => {
+                    warn(format!("{key} is only allowed in the global config"))
+                }
+                "env.pass" => self.pass.extend(strings(value).map_err(err)?),
+                "env.keep" => self.keep.extend(strings(value).map_err(err)?),
+                "env.preserve" => {
+                    self.preserve.extend(strings(value).map_err(err)?)
+                }
+                "cache.dir" => {
+                    let dir = PathBuf::from(string(value).map_err(err)?);
+                    if !dir.is_absolute() {
+                        return Err(err("expected an absolute path".into()));
+                    }
+                    self.cache_dir = Some(dir);
+                }
+                "cache.allow-stale" => {
+                    self.allow_stale = bool(value).map_err(err)?
+                }
+                "cache.lock-timeout" => {
+                    self.lock_timeout = string(value)
+                        .and_then(|s| parse_duration(&s))
+                        .map_err(err)?
+                }
+                "cache.gc-roots" => self.gc_roots = bool(value).map_err(err)?,
+                "cache.backend" => {
+                    self.backend = string(value)
+                        .and_then(|s| Backend::parse(&s))
+                        .map_err(err)?
+                }
+                "cache.ttl" => {
+                    self.ttl = string(value)
+                        .and_then(|s| parse_duration(&s))
+                        .map_err(err)?
+                }
+                "shell.rerun-hook" => {
+                    self.rerun_shell_hook = bool(value).map_err(err)?
+                }
+                "shell.eval-output" => {
+                    self.eval_output = string(value)
+                        .and_then(|s| Mode::parse(&s))
+                        .map_err(err)?
+                }
+                "gc.max-age" => {
+                    self.gc_max_age = string(value)
+                        .and_then(|s| parse_duration(&s))
+                        .map_err(err)?
+                        .pipe(Some)
+                }
+                "gc.max-size" => {
+                    self.gc_max_size = string(value)
+                        .and_then(|s| parse_size(&s))
+                        .map_err(err)?
+                        .pipe(Some)
+                }
+                _ => match key.strip_prefix("env.delimiters.") {
+                    Some(var) => {
+                        let delim = string(value).map_err(err)?;
+                        self.delimiters.insert(var.to_string(), delim);
+                    }
+                    None => warn(format!("unknown key {key}")),
+                },
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Flatten nested tables into `(dotted.key, value)` pairs.
+fn flatten(prefix: &str, table: Table, result: &mut Vec<(String, Value)>) {
+    for (key, value) in table {
+        let key = if prefix.is_empty() {
+            key
+        } else {
+            format!("{prefix}.{key}")
+        };
+        match value {
+            Value::Table(table) => flatten(&key, table, result),
+            value => result.push((key, value)),
+        }
+    }
+}
+
+fn string(value: Value) -> Result<String, String> {
+    match value {
+        Value::String(s) => Ok(s),
+        _ => Err("expected a string".into()),
+    }
+}
+
+fn bool(value: Value) -> Result<bool, String> {
+    match value {
+        Value::Boolean(b) => Ok(b),
+        _ => Err("expected a boolean".into()),
+    }
+}
+
+fn strings(value: Value) -> Result<Vec<String>, String> {
+    match value {
+        Value::Array(items) => items.into_iter().map(string).collect(),
+        _ => Err("expected an array of strings".into()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_flatten() {
+        let text = r#"
+top = 1
+env.pass = ["A", 'B']
+env.delimiters = { "X Y" = "\t" }
+[cache]
+allow-stale = true
+"#;
+        let mut result = Vec::new();
+        flatten("", toml::from_str(text).unwrap(), &mut result);
+        assert_eq!(
+            result,
+            vec![
+                ("cache.allow-stale".into(), Value::Boolean(true)),
+                ("env.delimiters.X Y".into(), Value::String("\t".into())),
+                (
+                    "env.pass".into(),
+                    Value::Array(vec![
+                        Value::String("A".into()),
+                        Value::String("B".into())
+                    ])
+                ),
+                ("top".into(), Value::Integer(1)),
+            ]
+        );
+    }
+
+    #[test]
+    fn layering() {
+        let mut config = Config::default();
+        config
+            .apply(
+                Path::new("config.toml"),
+                "[env]\nkeep = [\"A\"]\npreserve = [\"C\"]\n[cache]\ndir = \"/c\"\nlock-timeout = \"1m\"",
+                true,
+            )
+            .unwrap();
+        config
+            .apply(
+                Path::new("config.toml"),
+                "[env]\nkeep = [\"B\"]\npreserve = [\"D\"]\n[cache]\ndir = \"/p\"\nlock-timeout = \"5s\"",
+                false,
+            )
+            .unwrap();
+        assert_eq!(config.keep, vec!["A"]);
+        assert_eq!(config.preserve, vec!["C", "D"]);
+        assert_eq!(config.cache_dir, Some(PathBuf::from("/c")));
+        assert_eq!(config.lock_timeout, Duration::from_secs(5));
+
+        assert_eq!(
+            config
+                .apply(Path::new("config.toml"), "[gc]\nmax-size = 1", true)
+                .unwrap_err(),
+            "gc.max-size: expected a string"
+        );
+    }
+}
@@ -0,0 +1,293 @@
+//! `nix print-dev-env --json` backend
+//!
+//! By default, the environment is captured by running a command inside
+//! `nix-shell` (or `nix develop`) that dumps it.  With `--backend
+//! print-dev-env`, it is read from `nix print-dev-env --json` instead, which
+//! returns typed variables (exported, plain, arrays and associative arrays) and
+//! functions without starting a shell.  `shellHook` is not run during the
+//! capture, so it is run every time the shell is entered, as `nix develop`
+//! does.
+//!
+//! Entries of this backend have `print-dev-env` as the first argument, so they
+//! never collide with entries of the default backend.
+
+use crate::args::Args;
+use crate::bash::{is_identifier, is_literal_bash_string, quote};
+use crate::EnvMap;
+use serde_json::Value;
+use std::ffi::OsString;
+use std::fs::File;
+use std::path::Path;
+use std::process::Command;
+
+/// The first element of `NixShellInput::args` of entries of this backend.
+pub const PRINT_DEV_ENV: &str = "print-dev-env";
+
+/// Variables describing the build sandbox rather than the environment.  The
+/// same list is ignored by `nix develop`.
+const IGNORED_VARS: &[&str] = &[
+    "BASHOPTS",
+    "HOME",
+    "NIX_BUILD_TOP",
+    "NIX_ENFORCE_PURITY",
+    "NIX_LOG_FD",
+    "NIX_REMOTE",
+    "OLDPWD",
+    "PPID",
+    "PWD",
+    "SHELL",
+    "SHELLOPTS",
+    "SHLVL",
+    "TEMP",
+    "TEMPDIR",
+    "TERM",
+    "TMP",
+    "TMPDIR",
+    "TZ",
+    "UID",
+];
+
+/// `nix-shell` options that `nix` doesn't accept.  They don't affect the
+/// environment, so they are just dropped.
+const NIX_SHELL_ONLY_OPTIONS: &[&str] =
+    &["--no-build-hook", "--no-build-output"];
+
+/// How the environment is captured, see `--backend`.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Backend {
+    /// Dump the environment from a command run inside the shell.
+    Run,
+    /// `nix print-dev-env --json`.
+    PrintDevEnv,
+}
+
+impl Backend {
+    pub fn parse(s: &str) -> Result<Backend, String> {
+        match s {
+            "run" => Ok(Backend::Run),
+            "print-dev-env" => Ok(Backend::PrintDevEnv),
+            _ => Err(format!(
+                "invalid backend {s:?}, expected run or print-dev-env"
+            )),
+        }
+    }
+}
+
+/// `nix print-dev-env` arguments equivalent to (normalized) `nix-shell`
+/// arguments, and the weak arguments.  Return `None` for `-p` and `-E`, and
+/// for several files or attributes.
+pub fn from_nix_shell_args(
+    x: &Args,
+    pwd: &Path,
+) -> Option<(Vec<OsString>, Vec<OsString>)> {
+    if x.packages_or_expr {
+        return None;
+    }
+
+    let file = match &x.rest[..] {
+        [] => OsString::from("."),
+        [file] => file.clone(),
+        _ => return None,
+    };
+    // nix-shell prefers shell.nix in directories, `--file` takes default.nix.
+    let file = if file == "." && pwd.join("shell.nix").exists() {
+        OsString::from("./shell.nix")
+    } else {
+        file
+    };
+
+    let mut args = vec![PRINT_DEV_ENV.into(), "--file".into(), file];
+    let mut attr = None;
+    let mut it = x.other_kw.iter();
+    while let Some(arg) = it.next() {
+        if arg == "--attr" {
+            if attr.is_some() {
+                return None;
+            }
+            attr = it.next().cloned();
+        } else {
+            args.push(arg.clone());
+        }
+    }
+    args.extend(attr);
+
+    let weak_args = x
+        .weak_kw
+        .iter()
+        .filter(|arg| !NIX_SHELL_ONLY_OPTIONS.iter().any(|x| arg == x))
+        .cloned()
+        .collect();
+    Some((args, weak_args))
+}
+
+/// The command writing the environment as JSON to `json`.  The profile
+/// pointing to the built environment is written to `profile`.
+pub fn command(
+    args: &[OsString],
+    weak_args: &[OsString],
+    profile: &Path,
+    json: File,
+) -> Command {
+    let mut cmd = Command::new(concat!(env!("CNS_NIX"), "nix"));
+    cmd.arg(PRINT_DEV_ENV)
+        .arg("--extra-experimental-features")
+        .arg("nix-command flakes")
+        .arg("--json")
+        .arg("--profile")
+        .arg(profile)
+        .args(weak_args)
+        .args(args)
+        .stdout(json);
+    cmd
+}
+
+/// The environment in the same form as captured by the default backend.
+pub struct DevEnv {
+    pub env: EnvMap,
+    /// Non-exported variables and functions as a bash script.
+    pub shell: Vec<u8>,
+    pub shell_hook: Vec<u8>,
+}
+
+/// Parse the output of `nix print-dev-env --json`.
+pub fn parse(json: &[u8]) -> Result<DevEnv, String> {
+    let json: Value =
+        serde_json::from_slice(json).map_err(|e| e.to_string())?;
+    let variables = json
+        .get("variables")
+        .and_then(Value::as_object)
+        .ok_or("no variables")?;
+
+    let mut env = EnvMap::new();
+    let mut shell = Vec::new();
+    let mut shell_hook = Vec::new();
+    for (name, var) in variables {
+        if IGNORED_VARS.contains(&name.as_str())
+            || !is_identifier(name.as_bytes())
+        {
+            continue;
+        }
+        let value = var.get("value");
+        match (var.get("type").and_then(Value::as_str), value) {
+            (Some("exported"), Some(Value::String(value))) => {
+                env.insert(OsString::from(name), OsString::from(value));
+            }
+            (Some("var"), Some(Value::String(value))) => {
+                shell.extend(format!("{name}=").as_bytes());
+                shell.extend(quote(value.as_bytes()));
+                shell.push(b'\n');
+            }
+            (Some("array"), Some(Value::Array(items))) => {
+                shell.extend(format!("declare -a {name}=(").as_bytes());
+                for item in items.iter().filter_map(Value::as_str) {
+                    shell.extend(quote(item.as_bytes()));
+                    shell.push(b' ');
+                }
+                shell.extend(b")\n");
+            }
+            (Some("associative"), Some(Value::Object(items))) => {
+                shell.extend(format!("declare -A {name}=(").as_bytes());
+                for (k, v) in items {
+                    if let Some(v) = v.as_str() {
+                        shell.push(b'[');
+                        shell.extend(quote(k.as_bytes()));
+                        shell.extend(b"]=");
+                        shell.extend(quote(v.as_bytes()));
+                        shell.push(b' ');
+                    }
+                }
+                shell.extend(b")\n");
+            }
+            // E.g. "unknown" for variables bash can't describe.
+            _ => continue,
+        }
+        if name == "shellHook" {
+            if let Some(Value::String(value)) = value {
+                shell_hook = value.clone().into_bytes();
+            }
+        }
+    }
+
+    let functions = json.get("bashFunctions").and_then(Value::as_object);
+    for (name, body) in functions.into_iter().flatten() {
+        if let (true, Some(body)) =
+            (is_literal_bash_string(name.as_bytes()), body.as_str())
+        {
+            // The body is the text between the outer braces.
+            shell.extend(format!("{name} ()\n{{{body}}}\n").as_bytes());
+        }
+    }
+
+    Ok(DevEnv {
+        env,
+        shell,
+        shell_hook,
+    })
+}
+
+/// Combine the environment with the one `nix` was run with, like `nix develop`
+/// does.
+pub fn merge_env(dev_env: EnvMap, clean_env: &EnvMap) -> EnvMap {
+    let mut env = clean_env.clone();
+    for (var, mut val) in dev_env {
+        if let (true, Some(old)) = (var == "PATH", clean_env.get(&var)) {
+            val.push(":");
+            val.push(old);
+        }
+        env.insert(var, val);
+    }
+    env.insert(OsString::from("IN_NIX_SHELL"), OsString::from("pure"));
+    env
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::ffi::OsStr;
+
+    #[test]
+    fn test_parse() {
+        let json = br#"{
+            "bashFunctions": {"hello": "\n    echo \"hello $1\"\n"},
+            "variables": {
+                "PATH": {"type": "exported", "value": "/nix/store/a/bin"},
+                "HOME": {"type": "exported", "value": "/homeless-shelter"},
+                "name": {"type": "var", "value": "it's"},
+                "arr": {"type": "array", "value": ["x", "y z"]},
+                "assoc": {"type": "associative", "value": {"k": "v"}},
+                "shellHook": {"type": "var", "value": "echo hook"},
+                "BASH_VERSINFO": {"type": "unknown"},
+                "bad name": {"type": "var", "value": ""}
+            }
+        }"#;
+        let dev_env = parse(json).ok().unwrap();
+        let mut expected = EnvMap::new();
+        expected.insert("PATH".into(), "/nix/store/a/bin".into());
+        assert_eq!(dev_env.env, expected);
+        assert_eq!(dev_env.shell_hook, b"echo hook");
+        assert_eq!(
+            String::from_utf8(dev_env.shell).unwrap(),
+            concat!(
+                "declare -a arr=('x' 'y z' )\n",
+                "declare -A assoc=(['k']='v' )\n",
+                "name='it'\\''s'\n",
+                "shellHook='echo hook'\n",
+                "hello ()\n{\n    echo \"hello $1\"\n}\n",
+            )
+        );
+        assert!(parse(b"{}").is_err());
+    }
+
+    #[test]
+    fn test_merge_env() {
+        let mut clean_env = EnvMap::new();
+        clean_env.insert("PATH".into(), "/bin".into());
+        clean_env.insert("HOME".into(), "/home/user".into());
+        let mut dev_env = EnvMap::new();
+        dev_env.insert("PATH".into(), "/nix/store/a/bin".into());
+        let env = merge_env(dev_env, &clean_env);
+        assert_eq!(env[OsStr::new("PATH")], "/nix/store/a/bin:/bin");
+        assert_eq!(env[OsStr::new("HOME")], "/home/user");
+        assert_eq!(env[OsStr::new("IN_NIX_SHELL")], "pure");
+    }
+}
@@ -0,0 +1,162 @@
+//! Finding the derivation of a shell
+//!
+//! `nix-shell` doesn't tell which `.drv` file it has instantiated, but the
+//! output path `out` is available in the environment, and Nix keeps track of
+//! the derivations producing every output.  The command querying it is chosen
+//! by the Nix version, the others are tried in turn only if it fails.
+
+use crate::nix_version;
+use std::ffi::OsStr;
+use std::path::Path;
+use std::process::Command;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Method {
+    /// `nix-store --query --valid-derivers` (Nix 2.4+).
+    ValidDerivers,
+    /// `nix show-derivation` (Nix 2.3).
+    ShowDerivation,
+    /// `nix show-derivation` with `nix-command` enabled (Nix 2.4 to 2.14).
+    ShowDerivationExperimental,
+    /// `nix derivation show` (Nix 2.15+).
+    DerivationShow,
+}
+
+const METHODS: &[Method] = &[
+    Method::ValidDerivers,
+    Method::ShowDerivation,
+    Method::ShowDerivationExperimental,
+    Method::DerivationShow,
+];
+
+impl Method {
+    fn name(self) -> &'static str {
+        match self {
+            Method::ValidDerivers => "valid-derivers",
+            Method::ShowDerivation => "show-derivation",
+            Method::ShowDerivationExperimental => "show-derivation-nix-command",
+            Method::DerivationShow => "derivation-show",
+        }
+    }
+
+    fn command(self, out: &OsStr) -> Command {
+        let mut cmd = match self {
+            Method::ValidDerivers => {
+                let mut cmd =
+                    Command::new(concat!(env!("CNS_NIX"), "nix-store"));
+                cmd.args(["--query", "--valid-derivers"]);
+                cmd
+            }
+            Method::ShowDerivation => {
+                let mut cmd = Command::new(concat!(env!("CNS_NIX"), "nix"));
+                cmd.arg("show-derivation");
+                cmd
+            }
+            Method::ShowDerivationExperimental | Method::DerivationShow => {
+                let mut cmd = Command::new(concat!(env!("CNS_NIX"), "nix"));
+                cmd.args(["--extra-experimental-features", "nix-command"]);
+                if self == Method::DerivationShow {
+                    cmd.args(["derivation", "show"]);
+                } else {
+                    cmd.arg("show-derivation");
+                }
+                cmd
+            }
+        };
+        cmd.arg(out);
+        cmd
+    }
+
+    /// Extract the path to the `.drv` file from the output of the command.
+    fn parse(self, store_dir: &Path, stdout: &[u8]) -> Option<String> {
+        // Paths to .drv files are always in ASCII, so no information is lost.
+        let stdout = String::from_utf8_lossy(stdout);
+        let drv = match self {
+            Method::ValidDerivers => stdout
+                .lines()
+                .find(|line| line.ends_with(".drv"))?
+                .to_string(),
+            _ => {
+                let json: serde_json::Value =
+                    serde_json::from_str(&stdout).ok()?;
+                // Since Nix 2.33, derivations are wrapped into an object
+                // along with the format version.
+                let drvs = json.get("derivations").unwrap_or(&json);
+                drvs.as_object()?
+                    .keys()
+                    .find(|key| key.ends_with(".drv"))?
+                    .clone()
+            }
+        };
+        // Newer versions omit the store directory.
+        if drv.starts_with('/') {
+            Some(drv)
+        } else {
+            Some(store_dir.join(drv).to_string_lossy().into_owned())
+        }
+    }
+}
+
+/// Find the `.drv` file producing the output path `out`.
+pub fn find(out: &OsStr) -> Result<String, String> {
+    let store_dir = Path::new(out).parent().unwrap_or(Path::new("/nix/store"));
+    let preferred = if nix_version::get().at_least(2, 4) {
+        Method::ValidDerivers
+    } else {
+        Method::ShowDerivation
+    };
+
+    let mut errors = String::new();
+    let methods = std::iter::once(preferred)
+        .chain(METHODS.iter().copied().filter(|&m| m != preferred));
+    for method in methods {
+        let result = method
+            .command(out)
+            .output()
+            .map_err(|e| e.to_string())
+            .and_then(|output| {
+                if !output.status.success() {
+                    return Err(String::from_utf8_lossy(&output.stderr)
+                        .trim_end()
+                        .to_string());
+                }
+                method
+                    .parse(store_dir, &output.stdout)
+                    .ok_or_else(|| "no derivation found".to_string())
+            });
+        match result {
+            Ok(drv) => return Ok(drv),
+            Err(e) => errors.push_str(&format!("\n{}: {e}", method.name())),
+        }
+    }
+    Err(errors)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse() {
+        let store = Path::new("/nix/store");
+        let drv = "/nix/store/00000000000000000000000000000000-shell.drv";
+        assert_eq!(
+            Method::ValidDerivers.parse(store, format!("{drv}\n").as_bytes()),
+            Some(drv.to_string())
+        );
+        assert_eq!(Method::ValidDerivers.parse(store, b""), None);
+        assert_eq!(
+            Method::ShowDerivation
+                .parse(store, format!(r#"{{"{drv}": {{}}}}"#).as_bytes()),
+            Some(drv.to_string())
+        );
+        assert_eq!(
+            Method::DerivationShow.parse(
+                store,
+                br#"{"derivations": {"00000000000000000000000000000000-shell.drv": {}}, "version": 4}"#
+            ),
+            Some(drv.to_string())
+        );
+        assert_eq!(Method::DerivationShow.parse(store, b"{}"), None);
+    }
+}
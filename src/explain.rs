@@ -0,0 +1,135 @@
+//! `--explain`: report why the cache was missed
+//!
+//! If there is an entry for the same inputs, its trace is checked and every
+//! changed file is listed.  Otherwise, the entry with the most similar inputs
+//! is found and the differences (directory, arguments, environment variables)
+//! are reported.
+
+use crate::cache::{self, Entry};
+use crate::gc::format_duration;
+use crate::inspect::format_args;
+use crate::NixShellInput;
+use std::collections::BTreeSet;
+use std::ffi::{OsStr, OsString};
+use std::time::Duration;
+
+pub fn explain_miss(hash: &str, inp: &NixShellInput, ttl: Duration) {
+    let reasons = match Entry::load(hash) {
+        Some(entry) => explain_entry(&entry, ttl),
+        None => explain_inputs(inp),
+    };
+    for reason in reasons {
+        eprintln!("cached-nix-shell: explain: {reason}");
+    }
+}
+
+fn explain_entry(entry: &Entry, ttl: Duration) -> Vec<String> {
+    if !entry.drv.is_empty() && std::fs::symlink_metadata(&entry.drv).is_err() {
+        return vec![format!("{} was garbage-collected", entry.drv)];
+    }
+    if entry.expired(ttl) {
+        return vec![format!(
+            "the entry depends on the network and is older than its TTL ({})",
+            format_duration(ttl)
+        )];
+    }
+    let changes = entry.trace.changes();
+    if changes.is_empty() {
+        return vec!["the entry has just been updated".to_string()];
+    }
+    changes
+}
+
+fn explain_inputs(inp: &NixShellInput) -> Vec<String> {
+    let closest = cache::list_hashes()
+        .into_iter()
+        .filter_map(|hash| {
+            let entry = Entry::load(&hash)?;
+            let diff = diff_inputs(&entry.decode_inputs()?, inp);
+            Some((hash, diff))
+        })
+        .min_by_key(|(_, diff)| diff.len());
+    match closest {
+        None => vec!["there are no other cache entries".to_string()],
+        Some((hash, diff)) => std::iter::once(format!(
+            "no entry for these inputs, the closest one is {}:",
+            &hash[..12]
+        ))
+        .chain(diff.into_iter().map(|line| format!("  {line}")))
+        .collect(),
+    }
+}
+
+/// Describe differences between inputs of an old entry and the new inputs.
+fn diff_inputs(old: &NixShellInput, new: &NixShellInput) -> Vec<String> {
+    let mut result = Vec::new();
+    if old.pwd != new.pwd {
+        result.push(format!("directory: {:?} -> {:?}", old.pwd, new.pwd));
+    }
+    if old.args != new.args {
+        result.push(format!(
+            "arguments: {} -> {}",
+            format_args(old),
+            format_args(new)
+        ));
+    }
+    let vars = old
+        .env
+        .keys()
+        .chain(new.env.keys())
+        .filter(|var| old.env.get(*var) != new.env.get(*var))
+        .collect::<BTreeSet<_>>();
+    for var in vars {
+        result.push(format!(
+            "${}: {} -> {}",
+            var.to_string_lossy(),
+            show_var(old.env.get(var)),
+            show_var(new.env.get(var)),
+        ));
+    }
+    result
+}
+
+fn show_var(val: Option<&OsString>) -> String {
+    match val {
+        Some(val) => format!("{:?}", OsStr::new(val)),
+        None => "(unset)".to_string(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::PathBuf;
+
+    fn inp(pwd: &str, args: &[&str], env: &[(&str, &str)]) -> NixShellInput {
+        NixShellInput {
+            pwd: PathBuf::from(pwd),
+            env: env
+                .iter()
+                .map(|(k, v)| (OsString::from(k), OsString::from(v)))
+                .collect(),
+            args: args.iter().map(OsString::from).collect(),
+            weak_args: Vec::new(),
+        }
+    }
+
+    #[test]
+    fn test_diff_inputs() {
+        let a =
+            inp("/a", &["--pure", "--", "x.nix"], &[("A", "1"), ("B", "2")]);
+        assert!(diff_inputs(&a, &a).is_empty());
+
+        let b = inp("/b", &["--pure", "--", "y.nix"], &[("B", "3"), ("C", "")]);
+        assert_eq!(
+            diff_inputs(&a, &b),
+            vec![
+                r#"directory: "/a" -> "/b""#,
+                "arguments: -- x.nix -> -- y.nix",
+                r#"$A: "1" -> (unset)"#,
+                r#"$B: "2" -> "3""#,
+                r#"$C: (unset) -> """#,
+            ]
+        );
+    }
+}
@@ -0,0 +1,191 @@
+//! `--export FORMAT`: print the environment instead of starting a shell
+//!
+//! Only exported variables are printed; shell variables, functions and
+//! `shellHook` are not.  Values are written byte for byte, so values that are
+//! not valid UTF-8 survive every format except JSON, where they are written as
+//! `{"bytes": [...]}` objects instead of strings.
+//!
+//! The `direnv` format is a script for `eval` in `.envrc`.  Besides exporting
+//! the variables, it calls `watch_file` for every file the evaluation depended
+//! on, so direnv reloads the environment exactly when the cache would be
+//! updated.
+
+use crate::bash::{is_identifier, quote};
+use crate::EnvMap;
+use serde_json::Value;
+use std::ffi::OsString;
+use std::os::unix::ffi::OsStrExt;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Format {
+    /// A JSON object.
+    Json,
+    /// `NAME="value"` lines with `\\`, `\"`, `\$` and `\n` escapes.
+    Dotenv,
+    /// `NAME="value"` lines understood by systemd's `EnvironmentFile=`.
+    Systemd,
+    /// `NAME=value` records terminated by NUL, as printed by `env -0`.
+    Env0,
+    /// `export` and `watch_file` commands for direnv.
+    Direnv,
+}
+
+impl Format {
+    pub fn parse(s: &str) -> Result<Format, String> {
+        match s {
+            "json" => Ok(Format::Json),
+            "dotenv" => Ok(Format::Dotenv),
+            "systemd" => Ok(Format::Systemd),
+            "env0" => Ok(Format::Env0),
+            "direnv" => Ok(Format::Direnv),
+            _ => Err(format!(
+                "invalid export format {s:?}, expected json, dotenv, systemd, env0 or direnv"
+            )),
+        }
+    }
+}
+
+/// Format the environment.  Variables that can't be represented in the format
+/// are skipped with a warning.  `watch_files` are used by the `direnv` format
+/// only.
+pub fn export(
+    format: Format,
+    env: &EnvMap,
+    watch_files: &[OsString],
+) -> Vec<u8> {
+    if format == Format::Env0 {
+        return crate::serialize_env(env);
+    }
+
+    let mut result = Vec::new();
+    let mut json = serde_json::Map::new();
+    for (name, value) in env {
+        let (name, value) = (name.as_bytes(), value.as_bytes());
+        match format {
+            Format::Json => match std::str::from_utf8(name) {
+                Ok(name) => {
+                    json.insert(name.to_string(), json_value(value));
+                }
+                Err(_) => skip(name, format),
+            },
+            Format::Dotenv | Format::Systemd | Format::Direnv
+                if !is_identifier(name) =>
+            {
+                skip(name, format)
+            }
+            Format::Dotenv => {
+                result.extend(name);
+                result.extend(b"=\"");
+                for &c in value {
+                    match c {
+                        b'\\' | b'"' | b'$' => result.extend([b'\\', c]),
+                        b'\n' => result.extend(b"\\n"),
+                        _ => result.push(c),
+                    }
+                }
+                result.extend(b"\"\n");
+            }
+            Format::Systemd => {
+                result.extend(name);
+                result.extend(b"=\"");
+                // Newlines are kept as is inside double quotes.
+                for &c in value {
+                    if b"\\\"`$".contains(&c) {
+                        result.push(b'\\');
+                    }
+                    result.push(c);
+                }
+                result.extend(b"\"\n");
+            }
+            Format::Direnv => {
+                result.extend(b"export ");
+                result.extend(name);
+                result.push(b'=');
+                result.extend(quote(value));
+                result.push(b'\n');
+            }
+            Format::Env0 => unreachable!(),
+        }
+    }
+
+    if format == Format::Direnv {
+        for fname in watch_files {
+            result.extend(b"watch_file ");
+            result.extend(quote(fname.as_bytes()));
+            result.push(b'\n');
+        }
+    }
+
+    if format == Format::Json {
+        result = serde_json::to_vec_pretty(&json).unwrap();
+        result.push(b'\n');
+    }
+    result
+}
+
+fn json_value(value: &[u8]) -> Value {
+    match std::str::from_utf8(value) {
+        Ok(value) => Value::from(value),
+        Err(_) => serde_json::json!({ "bytes": value }),
+    }
+}
+
+fn skip(name: &[u8], format: Format) {
+    eprintln!(
+        "cached-nix-shell: warning: can't export {:?} as {format:?}, skipping",
+        String::from_utf8_lossy(name)
+    );
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::os::unix::ffi::OsStringExt;
+
+    fn sample() -> EnvMap {
+        let mut env = EnvMap::new();
+        env.insert("A".into(), "x \"$y\"\nz\\".into());
+        env.insert("B".into(), OsString::from_vec(b"\xf0".to_vec()));
+        env.insert("C-D".into(), "".into());
+        env
+    }
+
+    #[test]
+    fn test_export() {
+        assert_eq!(
+            String::from_utf8_lossy(&export(Format::Dotenv, &sample(), &[])),
+            "A=\"x \\\"\\$y\\\"\\nz\\\\\"\nB=\"\u{fffd}\"\n"
+        );
+        assert_eq!(
+            String::from_utf8_lossy(&export(Format::Systemd, &sample(), &[])),
+            "A=\"x \\\"\\$y\\\"\nz\\\\\"\nB=\"\u{fffd}\"\n"
+        );
+        let json: Value =
+            serde_json::from_slice(&export(Format::Json, &sample(), &[]))
+                .unwrap();
+        assert_eq!(
+            json,
+            serde_json::json!({
+                "A": "x \"$y\"\nz\\",
+                "B": {"bytes": [0xf0]},
+                "C-D": "",
+            })
+        );
+        assert_eq!(
+            export(Format::Env0, &sample(), &[]),
+            b"A=x \"$y\"\nz\\\0B=\xf0\0C-D=\0"
+        );
+        assert_eq!(
+            String::from_utf8_lossy(&export(
+                Format::Direnv,
+                &sample(),
+                &["/src/it's.nix".into()]
+            )),
+            concat!(
+                "export A='x \"$y\"\nz\\'\n",
+                "export B='\u{fffd}'\n",
+                "watch_file '/src/it'\\''s.nix'\n",
+            )
+        );
+    }
+}
@@ -0,0 +1,430 @@
+//! Flakes: `nix develop`
+//!
+//! `cached-nix-shell --develop [INSTALLABLE] [OPTIONS]... [--command CMD ARGS...]`
+//! caches the development shell of a flake in the same way as `nix-shell`:
+//! `nix develop` runs under trace-nix.so with a clean environment, and the
+//! result is merged with the current environment unless `--ignore-environment`
+//! is given.  Under `--wrap`, `nix develop` is substituted as well; other `nix`
+//! commands are passed to the real `nix`.
+//!
+//! A local flake is copied into the store as a whole, so every file of it is
+//! traced in addition to the files read during the evaluation: the files
+//! tracked by git (and the git index, which changes when a file is added), or
+//! the whole directory tree if the flake is not in a git repository.
+
+use crate::args::{get_next_arg, opt, Args, NixShellOption, RunMode};
+use crate::dev_env::{Backend, PRINT_DEV_ENV};
+use crate::export::Format;
+use crate::gc::parse_duration;
+use crate::output::Mode;
+use crate::trace::Trace;
+use crate::{absolute, config, nix_version, EnvMap, NixShellInput};
+use std::collections::VecDeque;
+use std::ffi::{OsStr, OsString};
+use std::os::unix::ffi::{OsStrExt, OsStringExt};
+use std::os::unix::process::CommandExt;
+use std::path::{Path, PathBuf};
+use std::process::{exit, Command};
+use ufcs::Pipe;
+
+/// The first element of `NixShellInput::args` of flake entries.
+pub const DEVELOP: &str = "develop";
+
+const OPTIONS_DB: &[NixShellOption] = &[
+    opt(false, 0, &["--impure"]),
+    opt(false, 0, &["--no-update-lock-file"]),
+    opt(false, 0, &["--no-write-lock-file"]),
+    opt(false, 0, &["--recreate-lock-file"]),
+    opt(false, 0, &["--refresh"]),
+    opt(false, 1, &["--include", "-I"]),
+    opt(false, 1, &["--inputs-from"]),
+    opt(false, 1, &["--output-lock-file"]),
+    opt(false, 1, &["--reference-lock-file"]),
+    opt(false, 1, &["--system"]),
+    opt(false, 1, &["--update-input"]),
+    opt(false, 2, &["--arg"]),
+    opt(false, 2, &["--argstr"]),
+    opt(false, 2, &["--override-input"]),
+    opt(true, 0, &["--fallback"]),
+    opt(true, 0, &["--keep-failed", "-K"]),
+    opt(true, 0, &["--keep-going"]),
+    opt(true, 0, &["--no-eval-cache"]),
+    opt(true, 0, &["--print-build-logs", "-L"]),
+    opt(true, 0, &["--quiet"]),
+    opt(true, 0, &["--show-trace"]),
+    opt(true, 0, &["--verbose", "-v"]),
+    opt(true, 1, &["--cores"]),
+    opt(true, 1, &["--experimental-features"]),
+    opt(true, 1, &["--extra-experimental-features"]),
+    opt(true, 1, &["--log-format"]),
+    opt(true, 1, &["--max-jobs", "-j"]),
+    opt(true, 2, &["--option"]),
+];
+
+/// Variables pointing to the temporary directory that `nix develop` removes
+/// on exit.
+const BUILD_TOP_VARS: &[&str] = &["TMP", "TMPDIR", "TEMP", "TEMPDIR"];
+
+enum ParseError {
+    /// The option is valid, but not supported by cached-nix-shell (e.g. it
+    /// runs a build phase instead of a shell).
+    Unsupported(OsString),
+    Invalid(String),
+}
+
+/// Parse `nix develop` arguments.  `rest` gets the installable (if any),
+/// `other_kw`/`weak_kw` get the options, `pure` is set by
+/// `--ignore-environment`.  The bool is true if `--file` or `--expr` is given,
+/// i.e. the installable is not a flake reference.
+fn parse(args: Vec<OsString>) -> Result<(Args, bool), ParseError> {
+    let mut res = Args::default();
+    let mut file_or_expr = false;
+    let mut it = VecDeque::<OsString>::from(args);
+    while let Some(arg) = get_next_arg(&mut it) {
+        let mut next = || -> Result<OsString, ParseError> {
+            it.pop_front().ok_or_else(|| {
+                ParseError::Invalid(format!(
+                    "flag {arg:?} requires more arguments"
+                ))
+            })
+        };
+        if let Some(db_item) = OPTIONS_DB
+            .iter()
+            .find(|it| it.names.iter().any(|&x| arg == x))
+        {
+            let vec = if db_item.is_weak {
+                &mut res.weak_kw
+            } else {
+                &mut res.other_kw
+            };
+            vec.push(arg.clone());
+            for _ in 0..db_item.arg_count {
+                vec.push(next()?);
+            }
+        } else if arg == "--file" || arg == "-f" || arg == "--expr" {
+            file_or_expr = true;
+            res.other_kw.push(arg.clone());
+            res.other_kw.push(next()?);
+        } else if arg == "--ignore-environment" || arg == "-i" {
+            res.pure = true;
+            res.other_kw.push(arg.clone());
+        } else if arg == "--command" || arg == "-c" {
+            res.run = RunMode::Exec(next()?, it.into());
+            break;
+        } else if arg == "--explain" {
+            res.explain = true;
+        } else if arg == "--allow-stale" {
+            res.allow_stale = true;
+        } else if arg == "--rerun-shell-hook" {
+            res.rerun_shell_hook = true;
+        } else if arg == "--backend" {
+            res.backend = next()?
+                .to_string_lossy()
+                .pipe(|s| Backend::parse(&s))
+                .map_err(ParseError::Invalid)?
+                .pipe(Some);
+        } else if arg == "--ttl" {
+            res.ttl = next()?
+                .to_string_lossy()
+                .pipe(|s| parse_duration(&s))
+                .map_err(ParseError::Invalid)?
+                .pipe(Some);
+        } else if arg == "--export" {
+            res.run = next()?
+                .to_string_lossy()
+                .pipe(|s| Format::parse(&s))
+                .map_err(ParseError::Invalid)?
+                .pipe(RunMode::Export);
+        } else if arg == "--eval-output" {
+            res.eval_output = next()?
+                .to_string_lossy()
+                .pipe(|s| Mode::parse(&s))
+                .map_err(ParseError::Invalid)?
+                .pipe(Some);
+        } else if arg.as_bytes().first() == Some(&b'-') || !res.rest.is_empty()
+        {
+            return Err(ParseError::Unsupported(arg));
+        } else {
+            res.rest.push(arg.clone());
+        }
+    }
+    Ok((res, file_or_expr))
+}
+
+/// Entry point for `nix` under `--wrap`.
+pub fn wrapped_nix(args: Vec<OsString>) {
+    match args.split_first() {
+        Some((cmd, args)) if cmd == DEVELOP => develop(args.to_vec()),
+        _ => exec_nix(&args),
+    }
+}
+
+/// `cached-nix-shell --develop ARGS...`, or `nix develop ARGS...` under
+/// `--wrap`.
+pub fn develop(args: Vec<OsString>) {
+    let (mut args, file_or_expr) = match parse(args.clone()) {
+        Ok(x) => x,
+        Err(ParseError::Invalid(e)) => crate::unwrap_or_errx(Err(e)),
+        Err(ParseError::Unsupported(arg)) => {
+            eprintln!(
+                "cached-nix-shell: {arg:?} is not supported, running nix develop without cache"
+            );
+            exec_nix(&[&[OsString::from(DEVELOP)], &args[..]].concat());
+        }
+    };
+
+    let nix = nix_version::get();
+    if !nix.at_least(2, 4) {
+        eprintln!(
+            "cached-nix-shell: nix develop requires Nix 2.4 or newer, found {}",
+            nix.version.map(|v| v.to_string()).unwrap_or_default()
+        );
+        exit(1);
+    }
+
+    // Normalize PWD in the same way as for nix-shell, so the same flake can
+    // be entered from different directories.
+    let cwd = std::env::current_dir().expect("Can't get PWD");
+    if args.rest.is_empty() && !file_or_expr {
+        args.rest.push(OsString::from("."));
+    }
+    let pwd = match args.rest.first_mut() {
+        Some(installable) if !file_or_expr => {
+            let (flake_ref, fragment) = split_fragment(installable);
+            if is_path(flake_ref) {
+                let pwd = absolute(Path::new(OsStr::from_bytes(flake_ref)));
+                *installable =
+                    [b".", fragment].concat().pipe(OsString::from_vec);
+                pwd
+            } else {
+                PathBuf::from(env!("CNS_VAR_EMPTY"))
+            }
+        }
+        _ => cwd,
+    };
+
+    let config = config::load(&pwd);
+    let (subcommand, other_kw) = match args.backend.unwrap_or(config.backend) {
+        Backend::Run => (DEVELOP, args.other_kw.clone()),
+        // The result doesn't depend on the environment.
+        Backend::PrintDevEnv => (
+            PRINT_DEV_ENV,
+            args.other_kw
+                .iter()
+                .filter(|arg| *arg != "-i" && *arg != "--ignore-environment")
+                .cloned()
+                .collect(),
+        ),
+    };
+    let inp = NixShellInput {
+        env: crate::clean_env(&config),
+        args: std::iter::once(OsString::from(subcommand))
+            .chain(other_kw)
+            .chain(args.rest.iter().cloned())
+            .collect(),
+        weak_args: args.weak_kw.clone(),
+        pwd,
+    };
+    let ambient = std::env::vars_os().collect();
+    let env = crate::cached_shell_env(&args, &config, &inp, &ambient);
+    crate::exec_run_mode(
+        std::mem::replace(&mut args.run, RunMode::InteractiveShell),
+        env,
+    );
+}
+
+/// Split `flake#attr` into `flake` and `#attr`.
+fn split_fragment(installable: &OsStr) -> (&[u8], &[u8]) {
+    let bytes = installable.as_bytes();
+    let pos = bytes.iter().position(|&b| b == b'#').unwrap_or(bytes.len());
+    bytes.split_at(pos)
+}
+
+/// True if the flake reference is a plain path (as opposed to an URL or a
+/// registry name).
+fn is_path(flake_ref: &[u8]) -> bool {
+    flake_ref.starts_with(b".") || flake_ref.starts_with(b"/")
+}
+
+/// The command evaluating the environment: `nix develop` running `env_cmd`.
+/// The profile pointing to the built environment is written to `profile`.
+pub fn command(
+    args: &[OsString],
+    weak_args: &[OsString],
+    env_cmd: &[u8],
+    profile: &Path,
+) -> Command {
+    let mut cmd = Command::new(concat!(env!("CNS_NIX"), "nix"));
+    cmd.arg(DEVELOP)
+        .arg("--extra-experimental-features")
+        .arg("nix-command flakes")
+        .arg("--profile")
+        .arg(profile)
+        .args(weak_args)
+        .args(args)
+        .args(["--command", "bash", "-c"])
+        .arg(OsStr::from_bytes(env_cmd));
+    cmd
+}
+
+/// Drop variables pointing to the temporary build directory of `nix develop`.
+pub fn clean_env(env: &mut EnvMap) {
+    if let Some(build_top) = env.remove(OsStr::new("NIX_BUILD_TOP")) {
+        for var in BUILD_TOP_VARS {
+            if env.get(OsStr::new(var)) == Some(&build_top) {
+                env.remove(OsStr::new(var));
+            }
+        }
+    }
+}
+
+/// True unless `nix develop` arguments refer to a file or an expression rather
+/// than to a flake.
+pub fn is_flake(args: &[OsString]) -> bool {
+    !args
+        .iter()
+        .any(|arg| arg == "--file" || arg == "-f" || arg == "--expr")
+}
+
+/// Add every file of the local flake in `pwd` (if any) to the trace.
+pub fn trace_source(pwd: &Path, trace: &mut Trace) {
+    let root = match find_root(pwd) {
+        Some(root) => root,
+        None => return,
+    };
+    trace.add_file(root.join("flake.nix").as_os_str());
+    trace.add_file(root.join("flake.lock").as_os_str());
+
+    match git_files(&root) {
+        Some((git_dir, files)) => {
+            trace.add_file(git_dir.join("index").as_os_str());
+            for file in files {
+                trace.add_file(file.as_os_str());
+            }
+        }
+        None => trace_tree(&root, trace),
+    }
+}
+
+/// The nearest directory containing `flake.nix`, looking up to the root of
+/// the git repository (as `nix` does).
+fn find_root(pwd: &Path) -> Option<PathBuf> {
+    for dir in pwd.ancestors() {
+        if dir.join("flake.nix").exists() {
+            return Some(dir.to_path_buf());
+        }
+        if dir.join(".git").exists() {
+            return None;
+        }
+    }
+    None
+}
+
+/// The git directory and the files tracked by git, if `root` is in a git
+/// repository.
+fn git_files(root: &Path) -> Option<(PathBuf, Vec<PathBuf>)> {
+    let git = |args: &[&str]| -> Option<Vec<u8>> {
+        let output = Command::new("git")
+            .arg("-C")
+            .arg(root)
+            .args(args)
+            .output()
+            .ok()?;
+        output.status.success().then_some(output.stdout)
+    };
+    let dirs = git(&["rev-parse", "--show-toplevel", "--absolute-git-dir"])?;
+    let (toplevel, git_dir) =
+        match dirs.split(|&b| b == b'\n').collect::<Vec<_>>()[..] {
+            [toplevel, git_dir, _] => (
+                PathBuf::from(OsStr::from_bytes(toplevel)),
+                PathBuf::from(OsStr::from_bytes(git_dir)),
+            ),
+            _ => return None,
+        };
+    let files = git(&["ls-files", "-z", "--full-name", ":/"])?
+        .split(|&b| b == 0)
+        .filter(|name| !name.is_empty())
+        .map(|name| toplevel.join(OsStr::from_bytes(name)))
+        .collect();
+    Some((git_dir, files))
+}
+
+fn trace_tree(dir: &Path, trace: &mut Trace) {
+    trace.add_dir(dir.as_os_str());
+    for entry in std::fs::read_dir(dir).into_iter().flatten().flatten() {
+        match entry.file_type() {
+            Ok(typ) if typ.is_dir() => trace_tree(&entry.path(), trace),
+            Ok(typ) if typ.is_file() => {
+                trace.add_file(entry.path().as_os_str())
+            }
+            _ => (),
+        }
+    }
+}
+
+/// Run the real `nix` the user has in `$PATH` (rather than our `--wrap`
+/// symlink or the one cached-nix-shell is built with).
+fn exec_nix(args: &[OsString]) -> ! {
+    let nix = nix_version::user_binary().unwrap_or_else(|| {
+        eprintln!("cached-nix-shell: couldn't find nix in $PATH");
+        exit(1);
+    });
+    let exec = Command::new(nix).args(args).exec();
+    eprintln!("cached-nix-shell: couldn't run nix: {exec}");
+    exit(1);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse_ok(args: &[&str]) -> (Args, bool) {
+        match parse(args.iter().map(OsString::from).collect()) {
+            Ok(x) => x,
+            Err(_) => panic!("can't parse {:?}", args),
+        }
+    }
+
+    #[test]
+    fn test_parse() {
+        let (args, file_or_expr) =
+            parse_ok(&[".#dev", "-Li", "--impure", "-c", "make", "-j4"]);
+        assert_eq!(args.rest, vec![OsString::from(".#dev")]);
+        assert_eq!(args.weak_kw, vec![OsString::from("-L")]);
+        assert_eq!(args.other_kw, vec!["-i", "--impure"]);
+        assert!(args.pure);
+        assert!(!file_or_expr);
+        assert!(matches!(
+            args.run,
+            RunMode::Exec(cmd, cmd_args) if cmd == "make" && cmd_args == ["-j4"]
+        ));
+
+        let (args, file_or_expr) = parse_ok(&["-f", "default.nix", "shell"]);
+        assert!(file_or_expr);
+        assert!(matches!(args.run, RunMode::InteractiveShell));
+
+        for args in
+            [&["--phase", "build"][..], &["a", "b"], &["--profile", "p"]]
+        {
+            assert!(matches!(
+                parse(args.iter().map(OsString::from).collect()),
+                Err(ParseError::Unsupported(_))
+            ));
+        }
+    }
+
+    #[test]
+    fn test_split_fragment() {
+        assert_eq!(
+            split_fragment(OsStr::new("../a#b")),
+            (&b"../a"[..], &b"#b"[..])
+        );
+        assert_eq!(
+            split_fragment(OsStr::new("nixpkgs")),
+            (&b"nixpkgs"[..], &b""[..])
+        );
+        assert!(is_path(b"."));
+        assert!(is_path(b"/a"));
+        assert!(!is_path(b"github:a/b"));
+    }
+}
@@ -0,0 +1,357 @@
+//! `cached-nix-shell --gc`: remove stale cache entries
+//!
+//! An entry is considered dead and always removed if it can't be loaded, its
+//! derivation has been garbage-collected by nix, or the directory it was
+//! evaluated in no longer exists.  Additionally, entries that have not been
+//! used for `--max-age` are removed, and then the least recently used entries
+//! are evicted until the total size fits into `--max-size`.  Pinned entries
+//! (see `--pin`) are kept unless they can't be loaded at all.
+
+use crate::cache::{self, Entry};
+use crate::{gcroots, unwrap_or_errx};
+use nix::fcntl::{flock, FlockArg};
+use std::collections::HashSet;
+use std::ffi::OsString;
+use std::fs::{read_dir, remove_file, File};
+use std::os::unix::io::AsRawFd;
+use std::path::Path;
+use std::process::exit;
+use std::time::{Duration, SystemTime};
+use ufcs::Pipe;
+
+/// Temporary files older than this are leftovers of crashed processes.
+const TMP_FILE_AGE: Duration = Duration::from_secs(60 * 60);
+
+/// Files used by the cache format prior to `.entry` files.
+const LEGACY_EXTENSIONS: &[&str] = &["inputs", "env", "trace", "drv"];
+
+struct Policy {
+    max_age: Option<Duration>,
+    max_size: Option<u64>,
+    dry_run: bool,
+}
+
+struct EntryFile {
+    hash: String,
+    size: u64,
+    last_used: SystemTime,
+}
+
+pub fn gc(args: Vec<OsString>) {
+    let policy = parse_args(args).pipe(unwrap_or_errx);
+    let dir = cache::dir();
+
+    let mut entries = Vec::new();
+    let mut locks = Vec::new();
+    let mut aux = Vec::new();
+    let mut garbage = Vec::new();
+    let dir_entries = match read_dir(dir) {
+        Ok(x) => x,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => exit(0),
+        Err(e) => {
+            eprintln!("cached-nix-shell: can't read {dir:?}: {e}");
+            exit(1);
+        }
+    };
+    for dir_entry in dir_entries.filter_map(Result::ok) {
+        let path = dir_entry.path();
+        let md = match dir_entry.metadata() {
+            Ok(md) if md.is_file() || md.file_type().is_symlink() => md,
+            _ => continue,
+        };
+        let name = dir_entry.file_name().to_string_lossy().into_owned();
+        let (stem, ext) = name.rsplit_once('.').unwrap_or((&name, ""));
+        if name.starts_with(".tmp") {
+            let age = md.modified().ok().and_then(|t| t.elapsed().ok());
+            if age.map(|age| age > TMP_FILE_AGE) == Some(true) {
+                garbage.push((path, "leftover temporary file".to_string()));
+            }
+        } else if ext == "entry" {
+            entries.push(EntryFile {
+                hash: stem.to_string(),
+                size: md.len(),
+                last_used: md.modified().unwrap_or(SystemTime::UNIX_EPOCH),
+            });
+        } else if ext == "lock" {
+            locks.push((stem.to_string(), path));
+        } else if ext == "pin" || ext == "log" {
+            aux.push((stem.to_string(), path));
+        } else if LEGACY_EXTENSIONS.contains(&ext) {
+            garbage.push((path, "old cache format".to_string()));
+        }
+    }
+
+    // Most recently used first.
+    entries.sort_by_key(|entry| std::cmp::Reverse(entry.last_used));
+
+    // Pinned entries are always kept, so they are accounted first.
+    let mut kept_size = entries
+        .iter()
+        .filter(|entry| cache::is_pinned(&entry.hash))
+        .map(|entry| entry.size)
+        .sum::<u64>();
+
+    let mut kept = HashSet::new();
+    let mut removed_size = 0;
+    for entry in entries {
+        let reason = if cache::is_pinned(&entry.hash) {
+            Entry::read(&dir.join(format!("{}.entry", entry.hash)))
+                .err()
+                .map(|e| format!("can't load: {e}"))
+        } else {
+            check_entry(dir, &entry, &policy).or_else(|| {
+                policy
+                    .max_size
+                    .filter(|&max_size| kept_size + entry.size > max_size)
+                    .map(|_| "cache size limit exceeded".to_string())
+            })
+        };
+        match reason {
+            Some(reason) => {
+                report(&policy, &entry.hash, &reason);
+                removed_size += entry.size;
+                if !policy.dry_run {
+                    remove(&dir.join(format!("{}.entry", entry.hash)));
+                    let _ = cache::set_pinned(&entry.hash, false);
+                    gcroots::unregister(&entry.hash);
+                }
+            }
+            None if cache::is_pinned(&entry.hash) => {
+                kept.insert(entry.hash);
+            }
+            None => {
+                kept_size += entry.size;
+                kept.insert(entry.hash);
+            }
+        }
+    }
+
+    for (path, reason) in garbage {
+        report(&policy, &path.to_string_lossy(), &reason);
+        if !policy.dry_run {
+            remove(&path);
+        }
+    }
+
+    if !policy.dry_run {
+        for (hash, path) in locks {
+            if !kept.contains(&hash) {
+                remove_lock(&path);
+            }
+        }
+        for (hash, path) in aux {
+            if !kept.contains(&hash) {
+                remove(&path);
+            }
+        }
+        gcroots::cleanup(&kept);
+    }
+
+    let verb = if policy.dry_run {
+        "would free"
+    } else {
+        "freed"
+    };
+    println!(
+        "{verb} {}, {} kept ({})",
+        format_size(removed_size),
+        kept.len(),
+        format_size(kept_size),
+    );
+    exit(0);
+}
+
+/// Return the reason to remove the entry, if any.
+fn check_entry(
+    dir: &Path,
+    entry: &EntryFile,
+    policy: &Policy,
+) -> Option<String> {
+    if let Some(max_age) = policy.max_age {
+        let age = entry.last_used.elapsed().unwrap_or_default();
+        if age > max_age {
+            return Some(format!("unused for {}", format_duration(age)));
+        }
+    }
+
+    let loaded = match Entry::read(&dir.join(format!("{}.entry", entry.hash))) {
+        Ok(loaded) => loaded,
+        Err(e) => return Some(format!("can't load: {e}")),
+    };
+    if !loaded.drv.is_empty() && std::fs::symlink_metadata(&loaded.drv).is_err()
+    {
+        return Some(format!("{} is garbage-collected", loaded.drv));
+    }
+    match loaded.decode_inputs() {
+        None => return Some("can't decode inputs".to_string()),
+        Some(inp) if !inp.pwd.is_dir() => {
+            return Some(format!("{:?} no longer exists", inp.pwd));
+        }
+        Some(_) => (),
+    }
+    None
+}
+
+fn report(policy: &Policy, name: &str, reason: &str) {
+    let verb = if policy.dry_run {
+        "would remove"
+    } else {
+        "removing"
+    };
+    println!("{verb} {name}: {reason}");
+}
+
+fn remove(path: &Path) {
+    if let Err(e) = remove_file(path) {
+        eprintln!("cached-nix-shell: can't remove {path:?}: {e}");
+    }
+}
+
+/// Remove a lock file unless some process is holding it.  Processes that have
+/// it open and lock it afterwards notice that and create a new one.
+fn remove_lock(path: &Path) {
+    if let Ok(file) = File::open(path) {
+        if flock(file.as_raw_fd(), FlockArg::LockExclusiveNonblock).is_ok() {
+            remove(path);
+        }
+    }
+}
+
+/// `cached-nix-shell --pin HASH...` and `cached-nix-shell --unpin HASH...`
+pub fn pin(hashes: Vec<OsString>, pinned: bool) {
+    if hashes.is_empty() {
+        eprintln!("cached-nix-shell: entry hash not specified");
+        exit(1);
+    }
+    for prefix in hashes {
+        let hash =
+            cache::resolve_hash(&prefix.to_string_lossy()).pipe(unwrap_or_errx);
+        if let Err(e) = cache::set_pinned(&hash, pinned) {
+            eprintln!("cached-nix-shell: can't update {hash}: {e}");
+            exit(1);
+        }
+        println!("{} {hash}", if pinned { "pinned" } else { "unpinned" });
+    }
+    exit(0);
+}
+
+fn parse_args(args: Vec<OsString>) -> Result<Policy, String> {
+    let mut policy = Policy {
+        max_age: crate::config::GLOBAL.gc_max_age,
+        max_size: crate::config::GLOBAL.gc_max_size,
+        dry_run: false,
+    };
+    let mut it = args.into_iter();
+    while let Some(arg) = it.next() {
+        let mut next = || -> Result<String, String> {
+            it.next()
+                .ok_or_else(|| format!("flag {arg:?} requires an argument"))?
+                .into_string()
+                .map_err(|x| format!("invalid argument {x:?}"))
+        };
+        if arg == "--max-age" {
+            policy.max_age = Some(parse_duration(&next()?)?);
+        } else if arg == "--max-size" {
+            policy.max_size = Some(parse_size(&next()?)?);
+        } else if arg == "--dry-run" {
+            policy.dry_run = true;
+        } else {
+            return Err(format!("unexpected arg {arg:?}"));
+        }
+    }
+    Ok(policy)
+}
+
+/// Parse a duration like `90s`, `30m`, `12h`, `7d` or `4w`.
+pub fn parse_duration(s: &str) -> Result<Duration, String> {
+    let err = || format!("invalid duration {s:?}, expected e.g. 30d or 12h");
+    let (num, unit) =
+        s.split_at(s.find(|c: char| !c.is_ascii_digit()).unwrap_or(s.len()));
+    let num: u64 = num.parse().map_err(|_| err())?;
+    let mult = match unit {
+        "s" | "" => 1,
+        "m" => 60,
+        "h" => 60 * 60,
+        "d" => 24 * 60 * 60,
+        "w" => 7 * 24 * 60 * 60,
+        _ => return Err(err()),
+    };
+    num.checked_mul(mult)
+        .map(Duration::from_secs)
+        .ok_or_else(|| format!("duration {s:?} is too large"))
+}
+
+/// Parse a size like `512K`, `100M` or `2G`.
+pub fn parse_size(s: &str) -> Result<u64, String> {
+    let err = || format!("invalid size {s:?}, expected e.g. 500M or 2G");
+    let (num, unit) =
+        s.split_at(s.find(|c: char| !c.is_ascii_digit()).unwrap_or(s.len()));
+    let num: u64 = num.parse().map_err(|_| err())?;
+    let shift = match unit.to_ascii_uppercase().as_str() {
+        "" => 0,
+        "K" => 10,
+        "M" => 20,
+        "G" => 30,
+        "T" => 40,
+        _ => return Err(err()),
+    };
+    // `checked_shl` doesn't detect bits shifted out.
+    num.checked_mul(1 << shift)
+        .ok_or_else(|| format!("size {s:?} is too large"))
+}
+
+pub fn format_size(size: u64) -> String {
+    const UNITS: &[&str] = &["B", "KiB", "MiB", "GiB", "TiB"];
+    let mut size = size as f64;
+    let mut unit = 0;
+    while size >= 1024.0 && unit + 1 < UNITS.len() {
+        size /= 1024.0;
+        unit += 1;
+    }
+    if unit == 0 {
+        format!("{size} {}", UNITS[unit])
+    } else {
+        format!("{size:.1} {}", UNITS[unit])
+    }
+}
+
+pub fn format_duration(d: Duration) -> String {
+    let secs = d.as_secs();
+    match secs {
+        0..=119 => format!("{secs}s"),
+        120..=7199 => format!("{}m", secs / 60),
+        7200..=172_799 => format!("{}h", secs / 3600),
+        _ => format!("{}d", secs / 86400),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse() {
+        assert_eq!(parse_duration("90"), Ok(Duration::from_secs(90)));
+        assert_eq!(parse_duration("5m"), Ok(Duration::from_secs(300)));
+        assert_eq!(parse_duration("2d"), Ok(Duration::from_secs(172_800)));
+        assert!(parse_duration("").is_err());
+        assert!(parse_duration("d").is_err());
+        assert!(parse_duration("5y").is_err());
+        assert!(parse_duration("99999999999999999999").is_err());
+        assert!(parse_duration("9999999999999999999w").is_err());
+
+        assert_eq!(parse_size("100"), Ok(100));
+        assert_eq!(parse_size("4k"), Ok(4096));
+        assert_eq!(parse_size("2G"), Ok(2 << 30));
+        assert!(parse_size("2GB").is_err());
+        assert!(parse_size("-1").is_err());
+        assert!(parse_size("17179869184G").is_err());
+    }
+
+    #[test]
+    fn format() {
+        assert_eq!(format_size(10), "10 B");
+        assert_eq!(format_size(1536), "1.5 KiB");
+        assert_eq!(format_duration(Duration::from_secs(3 * 86400)), "3d");
+    }
+}
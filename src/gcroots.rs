@@ -0,0 +1,193 @@
+//! Nix GC roots for cached environments
+//!
+//! Every entry gets a directory `gcroots/{hash}` in the cache with symlinks to
+//! its derivation and to every store path mentioned in its environment (e.g.
+//! in `PATH` or `buildInputs`).  These symlinks are registered as indirect GC
+//! roots in the same way as lorri does: a symlink to each of them is placed
+//! into `/nix/var/nix/gcroots/per-user/$USER/cached-nix-shell/`.  Thus
+//! `nix-collect-garbage` keeps everything a cached shell needs, and removing
+//! an entry with `--gc` releases its roots.
+
+use crate::{cache, EnvMap};
+use nix::unistd::{access, getuid, AccessFlags, User};
+use std::collections::{BTreeSet, HashSet};
+use std::ffi::{OsStr, OsString};
+use std::fs::{create_dir_all, read_dir, remove_dir_all, remove_file};
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::symlink;
+use std::path::{Path, PathBuf};
+
+/// Length of the hash part of a store path name.
+const STORE_HASH_LEN: usize = 32;
+
+/// Register GC roots for an entry, replacing the previous ones.
+pub fn register(hash: &str, drv: &str, env: &EnvMap) {
+    if let Err(e) = try_register(hash, drv, env) {
+        eprintln!("cached-nix-shell: warning: can't register gc roots: {e}");
+    }
+}
+
+/// Register GC roots for an entry unless it already has them.  Failures are
+/// silent: `register` has already reported them when the entry was stored.
+pub fn ensure(hash: &str, drv: &str, env: &EnvMap) {
+    let dir = cache::dir().join("gcroots").join(hash);
+    let has_roots = read_dir(dir)
+        .map(|mut entries| entries.next().is_some())
+        .unwrap_or(false);
+    if !has_roots {
+        let _ = try_register(hash, drv, env);
+    }
+}
+
+fn try_register(
+    hash: &str,
+    drv: &str,
+    env: &EnvMap,
+) -> Result<(), std::io::Error> {
+    // Check the per-user directory first, so a failure leaves nothing behind
+    // and costs little when it repeats on every cache hit.
+    let user_dir = user_roots_dir()?;
+    create_user_roots_dir(&user_dir)?;
+    unregister(hash);
+
+    let store_dir = Path::new(drv).parent().unwrap_or(Path::new("/nix/store"));
+    let paths = std::iter::once(PathBuf::from(drv))
+        .chain(env.values().flat_map(|v| store_paths(store_dir, v)))
+        .filter(|path| path.symlink_metadata().is_ok())
+        .collect::<BTreeSet<_>>();
+
+    let dir = cache::dir().join("gcroots").join(hash);
+    create_dir_all(&dir)?;
+    for path in paths {
+        let name = path.file_name().unwrap();
+        let link = dir.join(name);
+        symlink(&path, &link)?;
+        // Might be left from a cache in another directory.
+        let user_link = user_dir.join(user_link_name(hash, name));
+        let _ = remove_file(&user_link);
+        symlink(&link, user_link)?;
+    }
+    Ok(())
+}
+
+/// Remove GC roots of an entry.
+pub fn unregister(hash: &str) {
+    let dir = cache::dir().join("gcroots").join(hash);
+    if let Ok(user_dir) = user_roots_dir() {
+        for entry in read_dir(&dir).into_iter().flatten().flatten() {
+            let link = user_dir.join(user_link_name(hash, &entry.file_name()));
+            let _ = remove_file(link);
+        }
+    }
+    let _ = remove_dir_all(dir);
+}
+
+/// Remove GC roots of entries that are not in `keep`, as well as dangling
+/// links in the per-user directory.
+pub fn cleanup(keep: &HashSet<String>) {
+    let dir = cache::dir().join("gcroots");
+    for entry in read_dir(dir).into_iter().flatten().flatten() {
+        let hash = entry.file_name().to_string_lossy().into_owned();
+        if !keep.contains(&hash) {
+            unregister(&hash);
+        }
+    }
+    if let Ok(user_dir) = user_roots_dir() {
+        for entry in read_dir(user_dir).into_iter().flatten().flatten() {
+            if entry.path().metadata().is_err() {
+                let _ = remove_file(entry.path());
+            }
+        }
+    }
+}
+
+fn user_link_name(hash: &str, name: &OsStr) -> OsString {
+    let mut result = OsString::from(hash);
+    result.push("-");
+    result.push(name);
+    result
+}
+
+/// Create `per-user/$USER/cached-nix-shell`, but nothing above `per-user`:
+/// its absence means that nix is installed differently.
+fn create_user_roots_dir(user_dir: &Path) -> Result<(), std::io::Error> {
+    let per_user = user_dir.parent().and_then(Path::parent).unwrap();
+    if !per_user.is_dir() {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::NotFound,
+            format!("{per_user:?} does not exist"),
+        ));
+    }
+    create_dir_all(user_dir)?;
+    access(user_dir, AccessFlags::W_OK)?;
+    Ok(())
+}
+
+fn user_roots_dir() -> Result<PathBuf, std::io::Error> {
+    let state_dir = std::env::var_os("NIX_STATE_DIR")
+        .unwrap_or_else(|| OsString::from("/nix/var/nix"));
+    let user = User::from_uid(getuid())
+        .ok()
+        .flatten()
+        .map(|user| user.name)
+        .or_else(|| std::env::var("USER").ok())
+        .ok_or_else(|| {
+            std::io::Error::new(
+                std::io::ErrorKind::NotFound,
+                "can't determine user name",
+            )
+        })?;
+    Ok(Path::new(&state_dir)
+        .join("gcroots/per-user")
+        .join(user)
+        .join("cached-nix-shell"))
+}
+
+/// Find all store paths mentioned in a string, e.g.
+/// `/nix/store/...-bash/bin:/nix/store/...-coreutils/bin`.
+fn store_paths(store_dir: &Path, text: &OsStr) -> Vec<PathBuf> {
+    let prefix = [store_dir.as_os_str().as_bytes(), b"/"].concat();
+    let text = text.as_bytes();
+    let mut result = Vec::new();
+    let mut pos = 0;
+    while let Some(idx) = find(&text[pos..], &prefix) {
+        let start = pos + idx + prefix.len();
+        let len = text[start..]
+            .iter()
+            .position(|&c| !is_store_name_char(c))
+            .unwrap_or(text.len() - start);
+        let name = &text[start..start + len];
+        if name.len() > STORE_HASH_LEN + 1 && name[STORE_HASH_LEN] == b'-' {
+            result.push(store_dir.join(OsStr::from_bytes(name)));
+        }
+        pos = start + len;
+    }
+    result
+}
+
+/// Reference: https://github.com/NixOS/nix/blob/2.3.10/src/libstore/store-api.cc#L81-L92
+fn is_store_name_char(c: u8) -> bool {
+    c.is_ascii_alphanumeric() || b"+-._?=".contains(&c)
+}
+
+fn find(haystack: &[u8], needle: &[u8]) -> Option<usize> {
+    haystack.windows(needle.len()).position(|w| w == needle)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_store_paths() {
+        let a = "/nix/store/aaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaa-bash-5.2";
+        let b = "/nix/store/bbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbb-coreutils";
+        let text =
+            format!("{a}/bin:{b}/bin:/nix/store/short-path:/usr/bin {b}");
+        assert_eq!(
+            store_paths(Path::new("/nix/store"), OsStr::new(&text)),
+            vec![PathBuf::from(a), PathBuf::from(b), PathBuf::from(b)],
+        );
+        assert!(store_paths(Path::new("/nix/store"), OsStr::new("")).is_empty());
+    }
+}
@@ -0,0 +1,221 @@
+//! Git-aware trace records
+//!
+//! `builtins.fetchGit` of a local repository depends on the commit the
+//! repository is at, but to find it, Nix (or libgit2 inside it) reads a
+//! varying set of files under `.git`: `HEAD`, loose refs, `packed-refs`,
+//! objects...  Tracing these files would invalidate the entry on unrelated
+//! changes, e.g. when `git gc` packs the refs, and would miss the ones read by
+//! `git` subprocesses.  Instead, they are replaced with a single `g` record per
+//! repository holding its resolved `HEAD`, which is read directly from `.git`
+//! without running `git`.  The record is keyed on the git directory, so work
+//! trees created by `git worktree` and submodules, whose git directories are
+//! under `.git/worktrees` and `.git/modules` of another repository, are
+//! tracked by their own `HEAD`:
+//! ```text
+//! refs/heads/main 0123abcd...   HEAD points to a branch
+//! refs/heads/main -             ... that has no commits yet
+//! 0123abcd...                   detached HEAD
+//! -                             not a repository
+//! ```
+//! The index is traced as a regular file: for a dirty tree, `fetchGit` takes
+//! the files that are in the index.
+
+use crate::path_clean::PathClean;
+use std::ffi::OsStr;
+use std::fs::read_to_string;
+use std::path::{Path, PathBuf};
+
+/// Symbolic refs pointing to symbolic refs are followed this deep.
+const MAX_DEPTH: usize = 5;
+
+/// A traced file inside a `.git` directory.
+#[derive(Debug, PartialEq)]
+pub enum GitFile {
+    /// `HEAD` of the repository with the given git directory, or the `.git`
+    /// of its work tree.
+    Head(PathBuf),
+    /// The index, traced as a regular file.
+    Index,
+    /// Anything else, e.g. refs, objects, or files of a removed repository.
+    Other,
+}
+
+/// Classify `path` if it's inside a `.git` directory (or is the `.git`
+/// itself, unless it's neither a repository nor a link to one).
+pub fn classify(path: &Path) -> Option<GitFile> {
+    let dot_git = path
+        .ancestors()
+        .find(|dir| dir.file_name() == Some(OsStr::new(".git")))?;
+    if dot_git == path {
+        return git_dir(path.parent()?).map(GitFile::Head);
+    }
+    // The innermost git directory: `.git` itself, or one of a work tree or a
+    // submodule under it.
+    let git_dir = match path
+        .ancestors()
+        .skip(1)
+        .take_while(|dir| dir.starts_with(dot_git))
+        .find(|dir| is_git_dir(dir))
+    {
+        Some(dir) => dir,
+        None => return Some(GitFile::Other),
+    };
+    Some(match path.strip_prefix(git_dir).ok()?.to_str() {
+        Some("HEAD") => GitFile::Head(git_dir.to_path_buf()),
+        Some("index") => GitFile::Index,
+        _ => GitFile::Other,
+    })
+}
+
+/// The resolved `HEAD` of the repository with the git directory `git_dir`, in
+/// the format of `g` records.
+pub fn head(git_dir: &Path) -> String {
+    let head = match read_to_string(git_dir.join("HEAD")) {
+        Ok(head) => head,
+        Err(_) => return "-".into(),
+    };
+    match head.trim_end().strip_prefix("ref: ") {
+        Some(name) => {
+            let commit = resolve_ref(git_dir, name, MAX_DEPTH);
+            format!("{name} {}", commit.as_deref().unwrap_or("-"))
+        }
+        None => head.trim_end().to_string(),
+    }
+}
+
+/// `.git` of the work tree `root` itself, or the directory it points to in
+/// work trees created by `git worktree` and in submodules.
+fn git_dir(root: &Path) -> Option<PathBuf> {
+    let dot_git = root.join(".git");
+    if dot_git.is_dir() {
+        return Some(dot_git);
+    }
+    let link = read_to_string(&dot_git).ok()?;
+    Some(root.join(link.trim_end().strip_prefix("gitdir: ")?).clean())
+}
+
+/// The same check as git does: a git directory has `HEAD` and either objects
+/// of its own or a `commondir` pointing to the shared ones.
+fn is_git_dir(dir: &Path) -> bool {
+    dir.join("HEAD").is_file()
+        && (dir.join("objects").is_dir() || dir.join("commondir").is_file())
+}
+
+/// The directory shared by all work trees of the repository.
+fn common_dir(git_dir: &Path) -> PathBuf {
+    match read_to_string(git_dir.join("commondir")) {
+        Ok(dir) => git_dir.join(dir.trim_end()),
+        Err(_) => git_dir.to_path_buf(),
+    }
+}
+
+fn resolve_ref(git_dir: &Path, name: &str, depth: usize) -> Option<String> {
+    let common_dir = common_dir(git_dir);
+    let loose = read_to_string(git_dir.join(name))
+        .or_else(|_| read_to_string(common_dir.join(name)));
+    if let Ok(value) = loose {
+        return match value.trim_end().strip_prefix("ref: ") {
+            Some(target) if depth > 0 => {
+                resolve_ref(git_dir, target, depth - 1)
+            }
+            Some(_) => None,
+            None => Some(value.trim_end().to_string()),
+        };
+    }
+    read_to_string(common_dir.join("packed-refs"))
+        .ok()?
+        .lines()
+        .filter(|line| !line.starts_with('#') && !line.starts_with('^'))
+        .find_map(|line| {
+            let (commit, ref_name) = line.split_once(' ')?;
+            (ref_name == name).then(|| commit.to_string())
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs::{create_dir_all, write};
+
+    const A: &str = "1111111111111111111111111111111111111111";
+    const B: &str = "2222222222222222222222222222222222222222";
+
+    #[test]
+    fn test_classify() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path();
+        let git = root.join(".git");
+        let sub_git = git.join("modules/lib/sub");
+        let wt_git = git.join("worktrees/wt");
+        create_dir_all(git.join("objects")).unwrap();
+        create_dir_all(sub_git.join("objects")).unwrap();
+        create_dir_all(&wt_git).unwrap();
+        for dir in [&git, &sub_git, &wt_git] {
+            write(dir.join("HEAD"), "ref: refs/heads/main\n").unwrap();
+        }
+        write(wt_git.join("commondir"), "../..\n").unwrap();
+        create_dir_all(root.join("wt")).unwrap();
+        write(
+            root.join("wt/.git"),
+            format!("gitdir: {}\n", wt_git.display()),
+        )
+        .unwrap();
+        create_dir_all(root.join("lib/sub")).unwrap();
+        write(
+            root.join("lib/sub/.git"),
+            "gitdir: ../../.git/modules/lib/sub",
+        )
+        .unwrap();
+
+        let head = |dir: &Path| Some(GitFile::Head(dir.to_path_buf()));
+        assert_eq!(classify(&git), head(&git));
+        assert_eq!(classify(&git.join("HEAD")), head(&git));
+        assert_eq!(classify(&git.join("index")), Some(GitFile::Index));
+        assert_eq!(classify(&git.join("packed-refs")), Some(GitFile::Other));
+        assert_eq!(
+            classify(&git.join("refs/heads/main")),
+            Some(GitFile::Other)
+        );
+        assert_eq!(classify(&wt_git.join("HEAD")), head(&wt_git));
+        assert_eq!(classify(&wt_git.join("index")), Some(GitFile::Index));
+        assert_eq!(classify(&root.join("wt/.git")), head(&wt_git));
+        assert_eq!(classify(&sub_git.join("HEAD")), head(&sub_git));
+        assert_eq!(classify(&root.join("lib/sub/.git")), head(&sub_git));
+        assert_eq!(classify(&root.join("file")), None);
+        assert_eq!(classify(&root.join(".gitignore")), None);
+        assert_eq!(classify(&root.join("none/.git")), None);
+    }
+
+    #[test]
+    fn test_head() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path();
+        let git = root.join(".git");
+        assert_eq!(head(&git), "-");
+
+        create_dir_all(git.join("refs/heads")).unwrap();
+        write(git.join("HEAD"), "ref: refs/heads/main\n").unwrap();
+        assert_eq!(head(&git), "refs/heads/main -");
+
+        write(
+            git.join("packed-refs"),
+            format!("# pack-refs\n{A} refs/heads/main\n"),
+        )
+        .unwrap();
+        assert_eq!(head(&git), format!("refs/heads/main {A}"));
+
+        // Loose refs take precedence over packed ones.
+        write(git.join("refs/heads/main"), format!("{B}\n")).unwrap();
+        assert_eq!(head(&git), format!("refs/heads/main {B}"));
+
+        write(git.join("HEAD"), format!("{A}\n")).unwrap();
+        assert_eq!(head(&git), A);
+
+        // A work tree created by `git worktree add`.
+        let wt_git = git.join("worktrees/wt");
+        create_dir_all(&wt_git).unwrap();
+        write(wt_git.join("HEAD"), "ref: refs/heads/main\n").unwrap();
+        write(wt_git.join("commondir"), "../..\n").unwrap();
+        assert_eq!(head(&wt_git), format!("refs/heads/main {B}"));
+    }
+}
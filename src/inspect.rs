@@ -0,0 +1,245 @@
+//! `cached-nix-shell --list`, `--show` and `--status`: inspect the cache
+
+use crate::bash::quote;
+use crate::cache::{self, Entry};
+use crate::gc::{format_duration, format_size};
+use crate::{config, unwrap_or_errx, NixShellInput};
+use std::ffi::{OsStr, OsString};
+use std::io::Write;
+use std::os::unix::ffi::OsStrExt;
+use std::process::exit;
+use std::time::{Duration, SystemTime};
+use ufcs::Pipe;
+
+/// `cached-nix-shell --list`
+pub fn list(args: Vec<OsString>) {
+    if let Some(arg) = args.first() {
+        eprintln!("cached-nix-shell: unexpected arg {arg:?}");
+        exit(1);
+    }
+
+    let dir = cache::dir();
+    println!(
+        "HASH           USED     CREATED  EVAL    FILES  SIZE       PWD  ARGS"
+    );
+    for hash in cache::list_hashes() {
+        let fname = dir.join(format!("{hash}.entry"));
+        let md = match fname.metadata() {
+            Ok(md) => md,
+            Err(_) => continue,
+        };
+        let entry = match Entry::read(&fname) {
+            Ok(entry) => entry,
+            Err(e) => {
+                println!("{:<14} ({e})", &hash[..12]);
+                continue;
+            }
+        };
+        let inp = entry.decode_inputs();
+        println!(
+            "{:<12}{:<3}{:<9}{:<9}{:<8}{:<7}{:<11}{}  {}",
+            &hash[..12],
+            if cache::is_pinned(&hash) { "*" } else { "" },
+            ago(md.modified().ok()),
+            ago(Some(entry.created)),
+            format!("{:.1}s", entry.eval_duration.as_secs_f64()),
+            entry.trace.len(),
+            format_size(md.len()),
+            inp.as_ref()
+                .map(|inp| inp.pwd.to_string_lossy().into_owned())
+                .unwrap_or_else(|| "?".into()),
+            inp.as_ref().map(format_args).unwrap_or_default(),
+        );
+    }
+    exit(0);
+}
+
+/// `cached-nix-shell --show HASH [--env | --trace]`
+pub fn show(args: Vec<OsString>) {
+    let (prefix, what) = match &args[..] {
+        [prefix] => (prefix, None),
+        [prefix, what] if what == "--env" || what == "--trace" => {
+            (prefix, Some(what.as_os_str()))
+        }
+        _ => {
+            eprintln!("usage: cached-nix-shell --show HASH [--env | --trace]");
+            exit(1);
+        }
+    };
+    let hash =
+        cache::resolve_hash(&prefix.to_string_lossy()).pipe(unwrap_or_errx);
+    let fname = cache::dir().join(format!("{hash}.entry"));
+    let entry = match Entry::read(&fname) {
+        Ok(entry) => entry,
+        Err(e) => {
+            eprintln!("cached-nix-shell: can't load {fname:?}: {e}");
+            exit(1);
+        }
+    };
+
+    let mut out = std::io::stdout().lock();
+    if what == Some(OsStr::new("--env")) {
+        for (k, v) in &entry.env {
+            let _ = out.write_all(&assignment(k, v));
+        }
+        exit(0);
+    }
+    if what == Some(OsStr::new("--trace")) {
+        for line in entry.trace.describe() {
+            let _ = writeln!(out, "{line}");
+        }
+        exit(0);
+    }
+
+    let _ = writeln!(out, "hash:          {hash}");
+    let _ = writeln!(out, "pinned:        {}", cache::is_pinned(&hash));
+    let _ = writeln!(out, "created:       {}", ago(Some(entry.created)));
+    let last_used = fname.metadata().and_then(|md| md.modified()).ok();
+    let _ = writeln!(out, "last used:     {}", ago(last_used));
+    let _ = writeln!(out, "evaluated in:  {:?}", entry.eval_duration);
+    let drv = if entry.drv.is_empty() {
+        "unknown"
+    } else {
+        &entry.drv
+    };
+    let _ = writeln!(out, "derivation:    {drv}");
+    if let Some(expires) = entry.expires(ttl(&entry)) {
+        let expires = match expires.duration_since(SystemTime::now()) {
+            Ok(d) => format!("in {}", format_duration(d)),
+            Err(_) => format!("expired {}", ago(Some(expires))),
+        };
+        let _ = writeln!(out, "expires:       {expires}");
+    }
+    let _ = writeln!(out, "traced files:  {}", entry.trace.len());
+    let _ = writeln!(out, "env variables: {}", entry.env.len());
+    if let Some(inp) = entry.decode_inputs() {
+        let _ = writeln!(out, "pwd:           {}", inp.pwd.to_string_lossy());
+        let _ = writeln!(out, "args:          {}", format_args(&inp));
+        let _ = writeln!(out, "inputs env:");
+        for (k, v) in &inp.env {
+            let _ = out.write_all(b"  ");
+            let _ = out.write_all(&assignment(k, v));
+        }
+    }
+    exit(0);
+}
+
+/// The TTL of the entry according to the config of its directory.
+fn ttl(entry: &Entry) -> Duration {
+    match entry.decode_inputs() {
+        Some(inp) => config::load(&inp.pwd).ttl,
+        None => config::GLOBAL.ttl,
+    }
+}
+
+/// `cached-nix-shell --status`: describe the entry of the current shell and
+/// check whether it is still up to date.  Exits with 1 if it is not.
+pub fn status(args: Vec<OsString>) {
+    if let Some(arg) = args.first() {
+        eprintln!("cached-nix-shell: unexpected arg {arg:?}");
+        exit(1);
+    }
+    let var = |name| std::env::var_os(name).unwrap_or_default();
+    let hash = var("CACHED_NIX_SHELL_ENTRY");
+    if hash.is_empty() {
+        eprintln!("cached-nix-shell: not inside a cached-nix-shell");
+        exit(1);
+    }
+    let hash = hash.to_string_lossy();
+
+    let mut out = std::io::stdout().lock();
+    let _ = writeln!(out, "hash:          {hash}");
+    let _ = writeln!(
+        out,
+        "pwd:           {}",
+        var("CACHED_NIX_SHELL_PWD").to_string_lossy()
+    );
+    let _ = writeln!(
+        out,
+        "args:          {}",
+        var("CACHED_NIX_SHELL_ARGS").to_string_lossy()
+    );
+    let drv = var("CACHED_NIX_SHELL_DRV");
+    let _ = writeln!(
+        out,
+        "derivation:    {}",
+        if drv.is_empty() {
+            "unknown".into()
+        } else {
+            drv.to_string_lossy()
+        }
+    );
+    let _ = writeln!(
+        out,
+        "started as:    {}",
+        var("CACHED_NIX_SHELL_STATUS").to_string_lossy()
+    );
+
+    let entry = match Entry::load(&hash) {
+        Some(entry) => entry,
+        None => {
+            let _ = writeln!(out, "state:         removed from the cache");
+            exit(1);
+        }
+    };
+    let mut changes = entry.trace.changes();
+    if entry.expired(ttl(&entry)) {
+        changes.insert(
+            0,
+            "the entry depends on the network and has expired".into(),
+        );
+    }
+    let same_env = var("CACHED_NIX_SHELL_ENV_HASH")
+        == OsStr::new(&crate::reload::env_hash(&entry.env));
+    let state = match (same_env, changes.is_empty()) {
+        (true, true) => "up to date",
+        (true, false) => "outdated",
+        (false, true) => "the entry was updated after the shell was started",
+        (false, false) => {
+            "outdated, the entry was updated after the shell was started"
+        }
+    };
+    let _ = writeln!(out, "state:         {state}");
+    for change in &changes {
+        let _ = writeln!(out, "  {change}");
+    }
+    exit(if same_env && changes.is_empty() { 0 } else { 1 });
+}
+
+/// Format nix-shell arguments as a shell command line.  The `--pure` flag,
+/// which is always passed by cached-nix-shell, is omitted.
+pub fn format_args(inp: &NixShellInput) -> String {
+    String::from_utf8_lossy(&quoted_args(inp)).into_owned()
+}
+
+/// Like `format_args`, but keeps non-UTF-8 arguments intact.
+pub fn quoted_args(inp: &NixShellInput) -> Vec<u8> {
+    inp.args
+        .iter()
+        .skip_while(|arg| *arg == "--pure")
+        .map(|arg| quote_if_needed(arg))
+        .collect::<Vec<_>>()
+        .join(&b' ')
+}
+
+/// Format `NAME=value\n`, quoting the value if needed.
+fn assignment(k: &OsStr, v: &OsStr) -> Vec<u8> {
+    [k.as_bytes(), b"=", &quote_if_needed(v), b"\n"].concat()
+}
+
+fn quote_if_needed(s: &OsStr) -> Vec<u8> {
+    let is_plain =
+        |c: &u8| c.is_ascii_alphanumeric() || b"%+,-./:=@_".contains(c);
+    if !s.is_empty() && s.as_bytes().iter().all(is_plain) {
+        s.as_bytes().to_vec()
+    } else {
+        quote(s.as_bytes())
+    }
+}
+
+fn ago(t: Option<SystemTime>) -> String {
+    match t.and_then(|t| t.elapsed().ok()) {
+        Some(d) => format!("{} ago", format_duration(d)),
+        None => "?".into(),
+    }
+}
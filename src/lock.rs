@@ -0,0 +1,186 @@
+//! Per-entry cache locks
+//!
+//! When several `cached-nix-shell` processes miss the same cache entry at
+//! once (e.g. two terminals or `make -j` running shebang scripts), only the
+//! first one should run `nix-shell`.  The rest wait on an `flock(2)` placed on
+//! `{hash}.lock` and then reuse the freshly written entry.
+//!
+//! The lock is tied to an open file description, so the kernel releases it
+//! as soon as the evaluating process exits or gets killed; a waiter then
+//! acquires it, finds no entry and evaluates by itself.  The PID of the holder
+//! is written into the lock file only to make diagnostics more helpful.
+//!
+//! `--gc` removes lock files nobody holds.  A process that opened the file
+//! before the removal would lock an orphaned inode, so after locking, the
+//! file is checked to be still in place, and reopened otherwise.
+//!
+//! A lock can be handed over to a child process: the file descriptor is
+//! inherited, and its number is passed in [`FD_VAR`].
+
+use nix::errno::Errno;
+use nix::fcntl::{fcntl, flock, FcntlArg, FdFlag, FlockArg};
+use std::fs::{File, OpenOptions};
+use std::io::{Read, Seek, Write};
+use std::os::unix::fs::MetadataExt;
+use std::os::unix::io::{AsRawFd, FromRawFd, RawFd};
+use std::time::{Duration, Instant};
+
+/// Environment variable with the number of the inherited lock descriptor.
+pub const FD_VAR: &str = "__CNS_LOCK_FD";
+
+/// How long to wait for another process before giving up and evaluating
+/// without the lock.
+pub const LOCK_TIMEOUT: Duration = Duration::from_secs(600);
+
+const POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+/// An exclusive lock on a cache entry.  Released on drop, unless it's
+/// inherited by a child process.
+pub struct EntryLock {
+    file: File,
+}
+
+impl EntryLock {
+    pub fn fd(&self) -> RawFd {
+        self.file.as_raw_fd()
+    }
+}
+
+/// Let the lock `fd` be inherited across `exec`.  To be called in the child
+/// process between `fork` and `exec`.
+pub fn pass_to_child(fd: RawFd) -> nix::Result<()> {
+    fcntl(fd, FcntlArg::F_SETFD(FdFlag::empty())).map(drop)
+}
+
+/// Take over the lock of the entry passed by the parent process with
+/// [`pass_to_child`], if any.
+pub fn inherit(hash: &str) -> Option<EntryLock> {
+    let fd: RawFd = std::env::var(FD_VAR).ok()?.parse().ok()?;
+    std::env::remove_var(FD_VAR);
+    // Make sure the descriptor is still the lock file of this entry.
+    let expected = open_lock_file(hash).ok()?.metadata().ok()?;
+    let actual = nix::sys::stat::fstat(fd).ok()?;
+    if (actual.st_dev, actual.st_ino) != (expected.dev(), expected.ino()) {
+        return None;
+    }
+    fcntl(fd, FcntlArg::F_SETFD(FdFlag::FD_CLOEXEC)).ok()?;
+    let file = unsafe { File::from_raw_fd(fd) };
+    // The lock is already held through this descriptor, so it doesn't block.
+    flock(fd, FlockArg::LockExclusiveNonblock).ok()?;
+    Some(EntryLock { file })
+}
+
+pub struct Locked {
+    /// `None` if locking is not possible (e.g. unsupported by the file
+    /// system) or timed out.
+    pub lock: Option<EntryLock>,
+    /// True if another process held the lock while we were waiting, so the
+    /// entry might have been updated in the meantime.
+    pub waited: bool,
+}
+
+pub fn lock_entry(hash: &str, timeout: Duration) -> Locked {
+    let mut file = match open_lock_file(hash) {
+        Ok(file) => file,
+        Err(e) => {
+            eprintln!("cached-nix-shell: warning: can't create lock: {e}");
+            return Locked {
+                lock: None,
+                waited: false,
+            };
+        }
+    };
+
+    let start = Instant::now();
+    let mut waited = false;
+    loop {
+        match flock(file.as_raw_fd(), FlockArg::LockExclusiveNonblock) {
+            Ok(()) if is_current(&file, hash) => break,
+            Ok(()) => match open_lock_file(hash) {
+                Ok(new) => {
+                    file = new;
+                    continue;
+                }
+                Err(e) => {
+                    eprintln!(
+                        "cached-nix-shell: warning: can't create lock: {e}"
+                    );
+                    return Locked { lock: None, waited };
+                }
+            },
+            Err(Errno::EWOULDBLOCK) | Err(Errno::EINTR) => (),
+            Err(e) => {
+                eprintln!("cached-nix-shell: warning: can't lock cache: {e}");
+                return Locked { lock: None, waited };
+            }
+        }
+        if !waited {
+            eprintln!(
+                "cached-nix-shell: waiting for another process{} to update the cache",
+                holder_pid(&mut file)
+                    .map(|pid| format!(" (pid {pid})"))
+                    .unwrap_or_default()
+            );
+            waited = true;
+        }
+        if start.elapsed() >= timeout {
+            eprintln!(
+                "cached-nix-shell: warning: timed out after {timeout:?}, updating the cache without lock"
+            );
+            return Locked { lock: None, waited };
+        }
+        std::thread::sleep(POLL_INTERVAL);
+    }
+
+    let _ = write_pid(&mut file);
+    Locked {
+        lock: Some(EntryLock { file }),
+        waited,
+    }
+}
+
+/// Lock the entry without waiting.  Return `None` if it's held by another
+/// process or can't be locked.
+pub fn try_lock_entry(hash: &str) -> Option<EntryLock> {
+    loop {
+        let mut file = open_lock_file(hash).ok()?;
+        flock(file.as_raw_fd(), FlockArg::LockExclusiveNonblock).ok()?;
+        if is_current(&file, hash) {
+            let _ = write_pid(&mut file);
+            return Some(EntryLock { file });
+        }
+    }
+}
+
+fn open_lock_file(hash: &str) -> Result<File, std::io::Error> {
+    let fname = crate::cache::place_file(format!("{hash}.lock"))?;
+    OpenOptions::new()
+        .read(true)
+        .write(true)
+        .create(true)
+        .truncate(false)
+        .open(fname)
+}
+
+/// True if `file` is still the lock file of the entry, i.e. it wasn't removed
+/// by `--gc` since it was opened.
+fn is_current(file: &File, hash: &str) -> bool {
+    let fname = crate::cache::dir().join(format!("{hash}.lock"));
+    match (file.metadata(), std::fs::metadata(fname)) {
+        (Ok(a), Ok(b)) => (a.dev(), a.ino()) == (b.dev(), b.ino()),
+        _ => false,
+    }
+}
+
+fn holder_pid(file: &mut File) -> Option<u32> {
+    let mut text = String::new();
+    file.rewind().ok()?;
+    file.read_to_string(&mut text).ok()?;
+    text.trim().parse().ok()
+}
+
+fn write_pid(file: &mut File) -> Result<(), std::io::Error> {
+    file.set_len(0)?;
+    file.rewind()?;
+    writeln!(file, "{}", std::process::id())
+}
@@ -1,5 +1,7 @@
 use crate::args::Args;
 use crate::bash::is_literal_bash_string;
+use crate::cache::Entry;
+use crate::config::Config;
 use crate::path_clean::PathClean;
 use crate::trace::Trace;
 use itertools::{chain, Itertools};
@@ -8,7 +10,7 @@ use once_cell::sync::Lazy;
 use std::collections::{BTreeMap, HashSet};
 use std::env::current_dir;
 use std::ffi::{OsStr, OsString};
-use std::fs::{read, read_link, File};
+use std::fs::read;
 use std::io::{Read, Write};
 use std::os::unix::ffi::OsStrExt;
 use std::os::unix::prelude::OsStringExt;
@@ -16,14 +18,30 @@ use std::os::unix::process::CommandExt;
 use std::os::unix::process::ExitStatusExt;
 use std::path::{Path, PathBuf};
 use std::process::{exit, Command, Stdio};
-use std::time::Instant;
+use std::time::{Duration, Instant, SystemTime};
 use tempfile::NamedTempFile;
 use ufcs::Pipe;
 
 mod args;
 mod bash;
+mod cache;
+mod config;
+mod dev_env;
+mod drv;
+mod explain;
+mod export;
+mod flake;
+mod gc;
+mod gcroots;
+mod git;
+mod inspect;
+mod lock;
 mod nix_path;
+mod nix_version;
+mod output;
 mod path_clean;
+mod refresh;
+mod reload;
 mod shebang;
 mod trace;
 
@@ -31,8 +49,15 @@ type EnvMap = BTreeMap<OsString, OsString>;
 
 struct EnvOptions {
     env: EnvMap,
-    bashopts: OsString,
-    shellopts: OsString,
+    bashopts: Vec<OsString>,
+    shellopts: Vec<OsString>,
+    /// Non-exported variables and functions, see [`bash::DUMP_SHELL`].
+    shell: Vec<u8>,
+    /// `shellHook` to run before the command, if requested and it wasn't
+    /// already run during the evaluation.
+    shell_hook: Option<Vec<u8>>,
+    /// Files the environment depends on, for `--export direnv`.
+    watch_files: Vec<OsString>,
 }
 
 static XDG_DIRS: Lazy<xdg::BaseDirectories> = Lazy::new(|| {
@@ -53,17 +78,18 @@ fn serialize_env(env: &EnvMap) -> Vec<u8> {
 }
 
 /// Deserealize environment variables from `env -0` format.
-fn deserealize_env(vec: Vec<u8>) -> EnvMap {
+/// Return `None` if some record doesn't contain `=`.
+fn deserealize_env(vec: Vec<u8>) -> Option<EnvMap> {
     vec.split(|&b| b == 0)
         .filter(|&var| !var.is_empty()) // last entry has trailing NUL
         .map(|var| {
-            let pos = var.iter().position(|&x| x == b'=').unwrap();
-            (
+            let pos = var.iter().position(|&x| x == b'=')?;
+            Some((
                 OsStr::from_bytes(&var[0..pos]).to_owned(),
                 OsStr::from_bytes(&var[pos + 1..]).to_owned(),
-            )
+            ))
         })
-        .collect::<BTreeMap<_, _>>()
+        .collect::<Option<BTreeMap<_, _>>>()
 }
 
 fn serialize_args(args: &[OsString]) -> Vec<u8> {
@@ -75,6 +101,25 @@ fn serialize_args(args: &[OsString]) -> Vec<u8> {
     vec
 }
 
+fn deserialize_args(vec: &[u8]) -> Vec<OsString> {
+    let mut args = vec
+        .split(|&b| b == 0)
+        .map(|arg| OsStr::from_bytes(arg).to_owned())
+        .collect::<Vec<_>>();
+    args.pop(); // last arg has trailing NUL
+    args
+}
+
+/// Split the value of `$BASHOPTS` or `$SHELLOPTS`.
+fn split_options(opts: Option<OsString>) -> Vec<OsString> {
+    opts.unwrap_or_default()
+        .as_bytes()
+        .split(|&b| b == b':')
+        .filter(|opt| !opt.is_empty())
+        .map(|opt| OsStr::from_bytes(opt).to_owned())
+        .collect()
+}
+
 fn serialize_vecs(vecs: &[&[u8]]) -> Vec<u8> {
     let mut vec = Vec::new();
     for v in vecs {
@@ -84,6 +129,23 @@ fn serialize_vecs(vecs: &[&[u8]]) -> Vec<u8> {
     vec
 }
 
+/// Inverse of [`serialize_vecs`].
+fn deserialize_vecs(mut data: &[u8]) -> Option<Vec<&[u8]>> {
+    let mut result = Vec::new();
+    while !data.is_empty() {
+        let pos = data.iter().position(|&b| b == 0)?;
+        let len: usize =
+            std::str::from_utf8(&data[..pos]).ok()?.parse().ok()?;
+        data = &data[pos + 1..];
+        if data.len() < len {
+            return None;
+        }
+        result.push(&data[..len]);
+        data = &data[len..];
+    }
+    Some(result)
+}
+
 fn unwrap_or_errx<T>(x: Result<T, String>) -> T {
     match x {
         Ok(x) => x,
@@ -101,9 +163,41 @@ struct NixShellInput {
     weak_args: Vec<OsString>,
 }
 
+impl NixShellInput {
+    /// Serialize everything except `weak_args`.  The hash of the result is
+    /// used as a cache key.
+    fn serialize(&self) -> Vec<u8> {
+        serialize_vecs(&[
+            &serialize_env(&self.env),
+            &serialize_args(&self.args),
+            self.pwd.as_os_str().as_bytes(),
+        ])
+    }
+
+    fn deserialize(data: &[u8]) -> Option<NixShellInput> {
+        match deserialize_vecs(data)?[..] {
+            [env, args, pwd] => Some(NixShellInput {
+                pwd: PathBuf::from(OsStr::from_bytes(pwd)),
+                env: deserealize_env(env.to_vec())?,
+                args: deserialize_args(args),
+                weak_args: Vec::new(),
+            }),
+            _ => None,
+        }
+    }
+}
+
 struct NixShellOutput {
     env: EnvMap,
-    trace: trace::Trace,
+    bashopts: Vec<OsString>,
+    shellopts: Vec<OsString>,
+    shell: Vec<u8>,
+    shell_hook: Vec<u8>,
+    output: output::Output,
+    /// `None` if the trace is incomplete, then the result isn't cached.
+    trace: Option<trace::Trace>,
+    /// Variables read by the evaluation but not passed to it.
+    unpassed_env: Vec<OsString>,
     drv: String,
 }
 
@@ -168,48 +262,85 @@ fn absolute(path: &Path) -> PathBuf {
     }
 }
 
-fn args_to_inp(pwd: PathBuf, x: &Args) -> NixShellInput {
-    let mut args = Vec::new();
-
-    args.push(OsString::from("--pure"));
+/// Env vars to pass to `nix-shell --pure`.  Changes to these variables would
+/// invalidate the cache.
+fn clean_env(config: &Config) -> EnvMap {
+    let mut clean_env = BTreeMap::new();
+    let whitelist = &[
+        "HOME",
+        "NIX_PATH",
+        // tmp dir
+        "TMPDIR",
+        "XDG_RUNTIME_DIR",
+        // ssl-related
+        "CURL_CA_BUNDLE",
+        "GIT_SSL_CAINFO",
+        "NIX_SSL_CERT_FILE",
+        "SSL_CERT_FILE",
+        // Necessary if nix build caches are accessed via a proxy
+        "http_proxy",
+        "https_proxy",
+        "ftp_proxy",
+        "all_proxy",
+        "no_proxy",
+    ];
+    let whitelist = whitelist
+        .iter()
+        .copied()
+        .chain(config.pass.iter().map(String::as_str));
+    for var in whitelist {
+        if let Some(val) = std::env::var_os(var) {
+            clean_env.insert(OsString::from(var), val);
+        }
+    }
+    clean_env.insert(OsString::from("PATH"), minimal_essential_path());
+    clean_env
+}
 
-    let env = {
-        let mut clean_env = BTreeMap::new();
-        // Env vars to pass to `nix-shell --pure`. Changes to these variables
-        // would invalidate the cache.
-        let whitelist = &[
-            "HOME",
-            "NIX_PATH",
-            // tmp dir
-            "TMPDIR",
-            "XDG_RUNTIME_DIR",
-            // ssl-related
-            "CURL_CA_BUNDLE",
-            "GIT_SSL_CAINFO",
-            "NIX_SSL_CERT_FILE",
-            "SSL_CERT_FILE",
-            // Necessary if nix build caches are accessed via a proxy
-            "http_proxy",
-            "https_proxy",
-            "ftp_proxy",
-            "all_proxy",
-            "no_proxy",
-        ];
-        for var in whitelist {
-            if let Some(val) = std::env::var_os(var) {
-                clean_env.insert(OsString::from(var), val);
-            }
+/// [`clean_env`] with the `--keep` variables that are set, and the names of
+/// these variables.
+fn keep_env(x: &Args, config: &Config) -> (EnvMap, Vec<OsString>) {
+    let mut env = clean_env(config);
+    let mut kept = Vec::new();
+    let keep = config.keep.iter().map(OsString::from).chain(x.keep.clone());
+    for var in keep.unique() {
+        if let Some(val) = std::env::var_os(&var) {
+            env.insert(var.clone(), val);
+            kept.push(var);
         }
-        for var in x.keep.iter() {
-            if let Some(val) = std::env::var_os(var) {
-                clean_env.insert(var.clone(), val);
-                args.push("--keep".into());
-                args.push(var.clone());
+    }
+    (env, kept)
+}
+
+fn args_to_inp(pwd: PathBuf, x: &Args, config: &Config) -> NixShellInput {
+    if x.backend.unwrap_or(config.backend) == dev_env::Backend::PrintDevEnv {
+        match dev_env::from_nix_shell_args(x, &pwd) {
+            Some(_) if !nix_version::get().at_least(2, 4) => eprintln!(
+                "cached-nix-shell: warning: the print-dev-env backend requires Nix 2.4 or newer, falling back to run"
+            ),
+            Some((args, weak_args)) => {
+                return NixShellInput {
+                    pwd,
+                    env: keep_env(x, config).0,
+                    args,
+                    weak_args,
+                }
             }
+            None => eprintln!(
+                "cached-nix-shell: warning: can't use the print-dev-env backend with these arguments, falling back to run"
+            ),
         }
-        clean_env.insert(OsString::from("PATH"), minimal_essential_path());
-        clean_env
-    };
+    }
+
+    let mut args = Vec::new();
+
+    args.push(OsString::from("--pure"));
+
+    let (env, kept) = keep_env(x, config);
+    for var in kept {
+        args.push("--keep".into());
+        args.push(var);
+    }
 
     args.extend(x.other_kw.clone());
     args.push(OsString::from("--"));
@@ -223,23 +354,61 @@ fn args_to_inp(pwd: PathBuf, x: &Args) -> NixShellInput {
     }
 }
 
-fn run_nix_shell(inp: &NixShellInput) -> NixShellOutput {
+fn run_nix_shell(inp: &NixShellInput, mode: output::Mode) -> NixShellOutput {
+    let start = SystemTime::now();
     let trace_file = NamedTempFile::new().expect("can't create temporary file");
 
     let env_file = NamedTempFile::new().expect("can't create temporary file");
+    let shell_file = NamedTempFile::new().expect("can't create temporary file");
     let env_cmd = [
-        b"{ printf \"BASHOPTS=%s\\0SHELLOPTS=%s\\0\" \"${BASHOPTS-}\" \"${SHELLOPTS-}\" ; env -0; } >",
+        b"{ printf \"BASHOPTS=%s\\0SHELLOPTS=%s\\0__CNS_SHELL_HOOK=%s\\0\" \"${BASHOPTS-}\" \"${SHELLOPTS-}\" \"${shellHook-}\" ; env -0; } >",
         bash::quote(env_file.path().as_os_str().as_bytes()).as_slice(),
+        b"; {\n",
+        bash::DUMP_SHELL,
+        b"\n} >",
+        bash::quote(shell_file.path().as_os_str().as_bytes()).as_slice(),
     ]
     .concat();
 
-    let env = {
-        let status = Command::new(concat!(env!("CNS_NIX"), "nix-shell"))
-            .arg("--run")
+    let json_file = NamedTempFile::new().expect("can't create temporary file");
+    let profile_dir = tempfile::tempdir().expect("can't create temporary dir");
+    let profile = profile_dir.path().join("profile");
+    let (subcommand, nix_args) = match inp.args.split_first() {
+        Some((cmd, args))
+            if cmd == flake::DEVELOP || cmd == dev_env::PRINT_DEV_ENV =>
+        {
+            (Some(cmd.as_os_str()), args)
+        }
+        _ => (None, &inp.args[..]),
+    };
+    let print_dev_env = subcommand == Some(OsStr::new(dev_env::PRINT_DEV_ENV));
+    let mut cmd = if subcommand == Some(OsStr::new(flake::DEVELOP)) {
+        flake::command(nix_args, &inp.weak_args, &env_cmd, &profile)
+    } else if print_dev_env {
+        let json = json_file.reopen().expect("can't reopen temporary file");
+        dev_env::command(nix_args, &inp.weak_args, &profile, json)
+    } else {
+        let mut cmd = Command::new(concat!(env!("CNS_NIX"), "nix-shell"));
+        cmd.arg("--run")
             .arg(OsStr::from_bytes(&env_cmd))
             .args(&inp.weak_args)
-            .args(&inp.args)
-            .stderr(std::process::Stdio::inherit())
+            .args(&inp.args);
+        cmd
+    };
+    // The print-dev-env backend writes its result to stdout.
+    let capture = mode != output::Mode::Show;
+    if !print_dev_env {
+        cmd.stdout(match capture {
+            true => Stdio::piped(),
+            false => output::inherit_stdout(),
+        });
+    }
+    if capture {
+        cmd.stderr(Stdio::piped());
+    }
+
+    let (mut env, output) = {
+        let child = cmd
             .current_dir(&inp.pwd)
             .env_clear()
             .envs(&inp.env)
@@ -247,9 +416,20 @@ fn run_nix_shell(inp: &NixShellInput) -> NixShellOutput {
             .env("DYLD_INSERT_LIBRARIES", env!("CNS_TRACE_NIX_SO"))
             .env("TRACE_NIX", trace_file.path())
             .stdin(Stdio::null())
-            .status()
+            .spawn()
             .expect("failed to execute nix-shell");
+        let (status, output) = if capture {
+            output::capture(child, mode)
+        } else {
+            child
+                .wait_with_output()
+                .map(|o| (o.status, output::Output::new()))
+        }
+        .expect("failed to execute nix-shell");
         if !status.success() {
+            if mode == output::Mode::Hide {
+                output::replay(&output);
+            }
             eprintln!("cached-nix-shell: nix-shell: {status}");
             let code = status
                 .code()
@@ -257,20 +437,45 @@ fn run_nix_shell(inp: &NixShellInput) -> NixShellOutput {
                 .unwrap_or(255);
             exit(code);
         }
-        let mut env = read(env_file.path())
-            .expect("can't read an environment file")
-            .pipe(deserealize_env);
-        // Drop session variables exported by bash
-        env.remove(OsStr::new("OLDPWD"));
-        env.remove(OsStr::new("PWD"));
-        env.remove(OsStr::new("SHLVL"));
-        env.remove(OsStr::new("_"));
-        env
+        if print_dev_env {
+            (EnvMap::new(), output)
+        } else {
+            let mut env = read(env_file.path())
+                .expect("can't read an environment file")
+                .pipe(deserealize_env)
+                .expect("can't parse an environment file");
+            // Drop session variables exported by bash
+            env.remove(OsStr::new("OLDPWD"));
+            env.remove(OsStr::new("PWD"));
+            env.remove(OsStr::new("SHLVL"));
+            env.remove(OsStr::new("_"));
+            if subcommand.is_some() {
+                flake::clean_env(&mut env);
+            }
+            (env, output)
+        }
+    };
+    let (shell, shell_hook, bashopts, shellopts) = if print_dev_env {
+        let dev_env = read(json_file.path())
+            .expect("can't read a json file")
+            .pipe(|json| dev_env::parse(&json))
+            .unwrap_or_else(|e| {
+                eprintln!("cached-nix-shell: can't parse nix print-dev-env output: {e}");
+                exit(1)
+            });
+        env = dev_env::merge_env(dev_env.env, &inp.env);
+        // Like `nix develop`, don't apply shell options of the builder.
+        (dev_env.shell, dev_env.shell_hook, Vec::new(), Vec::new())
+    } else {
+        let shell = read(shell_file.path()).expect("can't read a shell file");
+        let shell_hook = env
+            .remove(OsStr::new("__CNS_SHELL_HOOK"))
+            .unwrap_or_default()
+            .into_vec();
+        let bashopts = split_options(env.remove(OsStr::new("BASHOPTS")));
+        let shellopts = split_options(env.remove(OsStr::new("SHELLOPTS")));
+        (shell, shell_hook, bashopts, shellopts)
     };
-
-    let env_out = env
-        .get(OsStr::new("out"))
-        .expect("expected to have `out` environment variable");
 
     let mut trace_file =
         trace_file.reopen().expect("can't reopen temporary file");
@@ -278,50 +483,64 @@ fn run_nix_shell(inp: &NixShellInput) -> NixShellOutput {
     trace_file
         .read_to_end(&mut trace_data)
         .expect("Can't read trace file");
-    let trace = Trace::load(trace_data);
-    if trace.check_for_changes() {
-        eprintln!("cached-nix-shell: some files are already updated, cache won't be reused");
-    }
-    std::mem::drop(trace_file);
-
-    let drv: String = {
-        // nix 2.3
-        let mut exec = Command::new(concat!(env!("CNS_NIX"), "nix"))
-            .arg("show-derivation")
-            .arg(env_out)
-            .output()
-            .expect("failed to execute nix show-derivation");
-        let mut stderr = exec.stderr.clone();
-        if !exec.status.success() {
-            // nix 2.4
-            exec = Command::new(concat!(env!("CNS_NIX"), "nix"))
-                .arg("show-derivation")
-                .arg("--extra-experimental-features")
-                .arg("nix-command")
-                .arg(env_out)
-                .output()
-                .expect("failed to execute nix show-derivation");
-            stderr.extend(b"\n");
-            stderr.extend(exec.stderr);
+    let mut unpassed_env = Vec::new();
+    let trace = match Trace::load_raw(trace_data) {
+        Ok(mut trace) => {
+            trace.resolve_git();
+            if subcommand.is_some() && flake::is_flake(nix_args) {
+                flake::trace_source(&inp.pwd, &mut trace);
+            }
+            trace.forget_racy_meta(start);
+            unpassed_env = trace.resolve_env(&inp.env);
+            if trace.check_for_changes() {
+                eprintln!("cached-nix-shell: some files are already updated, cache won't be reused");
+            }
+            Some(trace)
         }
-        if !exec.status.success() {
+        Err(trace::LoadError::Version) => {
             eprintln!(
-                "cached-nix-shell: failed to execute nix show-derivation"
+                "cached-nix-shell: can't parse the trace, {} is of an incompatible version",
+                env!("CNS_TRACE_NIX_SO")
             );
-            let _ = std::io::stderr().write_all(&stderr);
             exit(1);
         }
+        Err(trace::LoadError::Corrupt) => {
+            eprintln!("cached-nix-shell: warning: the trace is truncated, the result won't be cached");
+            None
+        }
+    };
+    std::mem::drop(trace_file);
 
-        // Path to .drv file is always in ASCII, so no information is lost.
-        let output = String::from_utf8_lossy(&exec.stdout);
-        let output: serde_json::Value =
-            serde_json::from_str(&output).expect("failed to parse json");
-        // The first key of the toplevel object contains the path to .drv file.
-        let (drv, _) = output.as_object().unwrap().into_iter().next().unwrap();
-        drv.clone()
+    let drv: String = if subcommand.is_some() {
+        // The built environment rather than the derivation, but it serves the
+        // same purpose: its closure contains everything the shell needs.
+        profile
+            .canonicalize()
+            .expect("can't resolve nix develop profile")
+            .to_string_lossy()
+            .into_owned()
+    } else {
+        // Only needed for gc roots, so a failure isn't fatal.
+        env.get(OsStr::new("out"))
+            .ok_or_else(|| " no `out` variable".to_string())
+            .and_then(|out| drv::find(out))
+            .unwrap_or_else(|e| {
+                eprintln!("cached-nix-shell: warning: can't find the derivation of the shell:{e}");
+                String::new()
+            })
     };
 
-    NixShellOutput { env, trace, drv }
+    NixShellOutput {
+        env,
+        bashopts,
+        shellopts,
+        shell,
+        shell_hook,
+        output,
+        trace,
+        unpassed_env,
+        drv,
+    }
 }
 
 fn run_script(
@@ -330,8 +549,11 @@ fn run_script(
     script_args: Vec<OsString>,
 ) {
     let nix_shell_args = Args::parse(nix_shell_args, true).pipe(unwrap_or_errx);
-    let inp = args_to_inp(absolute_dirname(&fname), &nix_shell_args);
-    let env = cached_shell_env(nix_shell_args.pure, &inp);
+    let pwd = absolute_dirname(&fname);
+    let config = config::load(&pwd);
+    let inp = args_to_inp(pwd, &nix_shell_args, &config);
+    let ambient = std::env::vars_os().collect();
+    let env = cached_shell_env(&nix_shell_args, &config, &inp, &ambient);
 
     let exec = if is_literal_bash_string(nix_shell_args.interpreter.as_bytes())
     {
@@ -379,7 +601,7 @@ fn run_from_args(args: Vec<OsString>) {
         // out: cd /var/empty; nix-shell -p ...
         PathBuf::from(env!("CNS_VAR_EMPTY"))
     } else if let [arg] = &mut args.rest[..] {
-        if arg == "" {
+        if arg.is_empty() {
             // in:  nix-shell ""
             // out: cd $PWD; nix-shell ""
             // nix-shell "" will use ./default.nix
@@ -419,21 +641,68 @@ fn run_from_args(args: Vec<OsString>) {
         current_dir().expect("Can't get PWD")
     };
 
-    let inp = args_to_inp(nix_shell_pwd, &args);
-    let env = cached_shell_env(args.pure, &inp);
+    let config = config::load(&nix_shell_pwd);
+    let inp = args_to_inp(nix_shell_pwd, &args, &config);
+    let ambient = std::env::vars_os().collect();
+    let env = cached_shell_env(&args, &config, &inp, &ambient);
+    exec_run_mode(args.run, env);
+}
 
-    let (cmd, cmd_args) = match args.run {
+/// Run the interactive shell or the command in the environment.
+fn exec_run_mode(run: args::RunMode, mut env: EnvOptions) {
+    // Shell variables and functions are sourced from an anonymous file.  It
+    // has to be kept open until exec.
+    let mut _state_file = None;
+    let shell_hook = match &env.shell_hook {
+        Some(hook) => [b"eval " as &[u8], &bash::quote(hook), b"\n"].concat(),
+        None => Vec::new(),
+    };
+    let (cmd, cmd_args) = match run {
         args::RunMode::InteractiveShell => {
+            if !env.shell.is_empty() || !shell_hook.is_empty() {
+                let (file, path) = bash::state_file(&env.shell, &shell_hook)
+                    .expect("can't create temporary file");
+                env.env.insert(OsString::from("__CNS_STATE"), path);
+                _state_file = Some(file);
+            }
             let mut args = vec!["--rcfile".into(), env!("CNS_RCFILE").into()];
             args.append(build_bash_options(&env).as_mut());
             ("bash".into(), args)
         }
         args::RunMode::Shell(cmd) => {
+            if !env.shell.is_empty() || !shell_hook.is_empty() {
+                // BASH_ENV is sourced by non-interactive shells.  Restore the
+                // original one, so it doesn't affect nested shells.
+                let epilogue = match env.env.get(OsStr::new("BASH_ENV")) {
+                    Some(orig) => [
+                        &shell_hook,
+                        b"BASH_ENV=" as &[u8],
+                        &bash::quote(orig.as_bytes()),
+                        b"\n. \"$BASH_ENV\"\n",
+                    ]
+                    .concat(),
+                    None => {
+                        [&shell_hook, b"unset BASH_ENV\n" as &[u8]].concat()
+                    }
+                };
+                let (file, path) = bash::state_file(&env.shell, &epilogue)
+                    .expect("can't create temporary file");
+                env.env.insert(OsString::from("BASH_ENV"), path);
+                _state_file = Some(file);
+            }
             let mut args = build_bash_options(&env);
             args.extend_from_slice(&["-c".into(), cmd]);
             ("bash".into(), args)
         }
         args::RunMode::Exec(cmd, cmd_args) => (cmd, cmd_args),
+        args::RunMode::Export(format) => {
+            let _ = std::io::stdout().write_all(&export::export(
+                format,
+                &env.env,
+                &env.watch_files,
+            ));
+            exit(0);
+        }
     };
 
     let exec = Command::new(cmd)
@@ -445,49 +714,279 @@ fn run_from_args(args: Vec<OsString>) {
     exit(1);
 }
 
-fn cached_shell_env(pure: bool, inp: &NixShellInput) -> EnvOptions {
-    let inputs = serialize_vecs(&[
-        &serialize_env(&inp.env),
-        &serialize_args(&inp.args),
-        inp.pwd.as_os_str().as_bytes(),
-    ]);
+/// `ambient` is the environment the shell is started from.
+fn cached_shell_env(
+    args: &Args,
+    config: &Config,
+    inp: &NixShellInput,
+    ambient: &EnvMap,
+) -> EnvOptions {
+    if let args::RunMode::Export(_) = args.run {
+        output::redirect_stdout();
+    }
+
+    let inputs = inp.serialize();
 
     let inputs_hash = blake3::hash(&inputs).to_hex().as_str().to_string();
 
-    let mut env = if let Some(env) = check_cache(&inputs_hash) {
+    let eval_output = args.eval_output.unwrap_or(config.eval_output);
+    let config = &Config {
+        eval_output,
+        ttl: args.ttl.unwrap_or(config.ttl),
+        ..config.clone()
+    };
+
+    let (entry, status) =
+        if let Some(entry) = check_cache(&inputs_hash, config.ttl) {
+            (entry, Status::Hit)
+        } else {
+            if args.explain {
+                explain::explain_miss(&inputs_hash, inp, config.ttl);
+            }
+            match (args.allow_stale || config.allow_stale)
+                .then(|| refresh::use_stale(&inputs_hash, inp, config))
+                .flatten()
+            {
+                Some(entry) => (entry, Status::Stale),
+                None => {
+                    match update_cache_locked(
+                        &inputs_hash,
+                        inputs,
+                        inp,
+                        config,
+                        ambient,
+                    ) {
+                        (entry, true) => (entry, Status::Evaluated),
+                        (entry, false) => (entry, Status::Hit),
+                    }
+                }
+            }
+        };
+    let evaluated = status == Status::Evaluated;
+    // Entries stored with `gc-roots = false` have none.
+    if config.gc_roots && !evaluated {
+        gcroots::ensure(&inputs_hash, &entry.drv, &entry.env);
+    }
+    // The print-dev-env backend doesn't run the hook during the capture.
+    let print_dev_env = inp.args.first().map(OsString::as_os_str)
+        == Some(OsStr::new(dev_env::PRINT_DEV_ENV));
+    let shell_hook = !entry.shell_hook.is_empty()
+        && (print_dev_env
+            || (args.rerun_shell_hook || config.rerun_shell_hook)
+                && !evaluated);
+    // The hook prints its messages again by itself.
+    if eval_output == output::Mode::Replay && !evaluated && !shell_hook {
+        output::replay(&entry.output);
+    }
+
+    // Keep the exported environment the same for hits and evaluations.
+    let status = match args.run {
+        args::RunMode::Export(_) => None,
+        _ => Some(status),
+    };
+    EnvOptions {
+        env: shell_env(
+            &entry,
+            inp,
+            &inputs_hash,
+            status,
+            args.pure,
+            ambient,
+            config,
+        ),
+        shellopts: entry.shellopts,
+        bashopts: entry.bashopts,
+        shell: entry.shell,
+        shell_hook: shell_hook.then_some(entry.shell_hook),
+        watch_files: entry
+            .trace
+            .files()
+            .into_iter()
+            .map(OsStr::to_os_string)
+            .collect(),
+    }
+}
+
+/// How the entry was obtained, see `CACHED_NIX_SHELL_STATUS`.
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Status {
+    Hit,
+    Evaluated,
+    /// An outdated entry, see `--allow-stale`.
+    Stale,
+}
+
+impl Status {
+    fn as_str(self) -> &'static str {
+        match self {
+            Status::Hit => "hit",
+            Status::Evaluated => "evaluated",
+            Status::Stale => "stale",
+        }
+    }
+}
+
+/// The environment of a shell: the captured one combined with the `ambient`
+/// one, plus the `CACHED_NIX_SHELL_*` variables describing the entry, unless
+/// `status` is `None`.
+fn shell_env(
+    entry: &Entry,
+    inp: &NixShellInput,
+    hash: &str,
+    status: Option<Status>,
+    pure: bool,
+    ambient: &EnvMap,
+    config: &Config,
+) -> EnvMap {
+    let mut env = entry.env.clone();
+    env.insert(OsString::from("IN_CACHED_NIX_SHELL"), OsString::from("1"));
+    if let Some(status) = status {
+        let vars = [
+            ("CACHED_NIX_SHELL_ENTRY", OsString::from(hash)),
+            (
+                "CACHED_NIX_SHELL_ENV_HASH",
+                reload::env_hash(&entry.env).into(),
+            ),
+            ("CACHED_NIX_SHELL_DRV", OsString::from(&entry.drv)),
+            ("CACHED_NIX_SHELL_STATUS", OsString::from(status.as_str())),
+            ("CACHED_NIX_SHELL_PWD", inp.pwd.clone().into_os_string()),
+            (
+                "CACHED_NIX_SHELL_ARGS",
+                OsString::from_vec(inspect::quoted_args(inp)),
+            ),
+        ];
+        for (var, val) in vars {
+            env.insert(OsString::from(var), val);
+        }
+    }
+    let env = if pure {
         env
     } else {
-        eprintln!("cached-nix-shell: updating cache");
-        let start = Instant::now();
-        let outp = run_nix_shell(inp);
-        eprintln!("cached-nix-shell: done in {:?}", start.elapsed());
-
-        // TODO: use flock
-        cache_write(&inputs_hash, "inputs", &inputs);
-        cache_write(&inputs_hash, "env", &serialize_env(&outp.env));
-        cache_write(&inputs_hash, "trace", &outp.trace.serialize());
-        cache_symlink(&inputs_hash, "drv", &outp.drv);
-
-        outp.env
+        merge_impure_env(env, ambient, config)
     };
+    merge_env(env, ambient, config)
+}
 
-    let shellopts = env.remove(OsStr::new("SHELLOPTS")).unwrap_or_default();
-    let bashopts = env.remove(OsStr::new("BASHOPTS")).unwrap_or_default();
-    env.insert(OsString::from("IN_CACHED_NIX_SHELL"), OsString::from("1"));
+/// Update the cache entry unless another process is already doing it, in
+/// which case wait for it and reuse its result.  The flag is set if the entry
+/// was evaluated by this process.
+fn update_cache_locked(
+    hash: &str,
+    inputs: Vec<u8>,
+    inp: &NixShellInput,
+    config: &Config,
+    ambient: &EnvMap,
+) -> (Entry, bool) {
+    let lock::Locked {
+        lock: _lock,
+        waited,
+    } = lock::lock_entry(hash, config.lock_timeout);
+    match waited.then(|| check_cache(hash, config.ttl)).flatten() {
+        // Another process has just updated the entry.
+        Some(entry) => (entry, false),
+        None => (update_cache(hash, inputs, inp, config, ambient), true),
+    }
+}
 
-    EnvOptions {
-        env: merge_env(if pure { env } else { merge_impure_env(env) }),
-        shellopts,
-        bashopts,
+fn update_cache(
+    hash: &str,
+    inputs: Vec<u8>,
+    inp: &NixShellInput,
+    config: &Config,
+    ambient: &EnvMap,
+) -> Entry {
+    eprintln!("cached-nix-shell: updating cache");
+    let created = SystemTime::now();
+    let start = Instant::now();
+    let outp = run_nix_shell(inp, config.eval_output);
+    let eval_duration = start.elapsed();
+    eprintln!("cached-nix-shell: done in {eval_duration:?}");
+    for var in outp
+        .unpassed_env
+        .iter()
+        .filter(|&v| ambient.contains_key(v))
+    {
+        eprintln!(
+            "cached-nix-shell: warning: {var:?} is read by the evaluation but isn't passed to it, use --keep {}",
+            var.to_string_lossy()
+        );
+    }
+
+    let complete = outp.trace.is_some();
+    let trace = outp.trace.unwrap_or_default();
+    let entry = Entry {
+        inputs,
+        env: outp.env,
+        bashopts: outp.bashopts,
+        shellopts: outp.shellopts,
+        shell: outp.shell,
+        shell_hook: outp.shell_hook,
+        // Only replayed in the replay mode.
+        output: match config.eval_output {
+            output::Mode::Replay => outp.output,
+            _ => output::Output::new(),
+        },
+        drv: outp.drv,
+        created,
+        eval_duration,
+        network: uses_network(&trace, &inp.env),
+        trace,
+    };
+    if !complete {
+        return entry;
+    }
+    match entry.store(hash) {
+        Err(e) => eprintln!("Warning: can't store cache: {e}"),
+        Ok(()) if config.gc_roots => {
+            gcroots::register(hash, &entry.drv, &entry.env)
+        }
+        Ok(()) => (),
     }
+    entry
 }
 
-// Merge ambient (impure) environment into cached env.
-fn merge_impure_env(mut env: EnvMap) -> EnvMap {
+/// True if the evaluation used the fetcher cache of Nix in `~/.cache/nix`,
+/// i.e. its result depends on the network (`builtins.fetchTarball`,
+/// `fetchGit`, etc.).  The evaluation cache of flakes lives there as well,
+/// but doesn't count.
+fn uses_network(trace: &Trace, env: &EnvMap) -> bool {
+    let cache_dir = match (
+        env.get(OsStr::new("XDG_CACHE_HOME")),
+        env.get(OsStr::new("HOME")),
+    ) {
+        (Some(dir), _) => PathBuf::from(dir),
+        (None, Some(home)) => Path::new(home).join(".cache"),
+        (None, None) => return false,
+    }
+    .join("nix");
+    trace.files().into_iter().any(|fname| {
+        Path::new(fname).strip_prefix(&cache_dir).is_ok_and(|rel| {
+            !rel.as_os_str().is_empty()
+                && !rel.to_string_lossy().starts_with("eval-cache-")
+        })
+    })
+}
+
+/// Variables whose ambient values are appended to the cached ones in impure
+/// mode, and their delimiters.
+fn delimiters(config: &Config) -> EnvMap {
     let mut delim = EnvMap::new();
     delim.insert(OsString::from("PATH"), OsString::from(":"));
     delim.insert(OsString::from("HOST_PATH"), OsString::from(":"));
     delim.insert(OsString::from("XDG_DATA_DIRS"), OsString::from(":"));
+    for (var, d) in &config.delimiters {
+        delim.insert(OsString::from(var), OsString::from(d));
+    }
+    delim
+}
+
+// Merge ambient (impure) environment into cached env.
+fn merge_impure_env(
+    mut env: EnvMap,
+    ambient: &EnvMap,
+    config: &Config,
+) -> EnvMap {
+    let delim = delimiters(config);
 
     // Set to "/no-cert-file.crt" by setup.sh for pure envs.
     env.remove(OsStr::new("SSL_CERT_FILE"));
@@ -495,10 +994,10 @@ fn merge_impure_env(mut env: EnvMap) -> EnvMap {
 
     env.insert(OsString::from("IN_NIX_SHELL"), OsString::from("impure"));
 
-    for (var, val) in std::env::vars_os() {
+    for (var, val) in ambient {
         env.entry(var.clone())
             .and_modify(|old_val| {
-                if let Some(d) = delim.get(&var) {
+                if let Some(d) = delim.get(var) {
                     *old_val = OsString::from(OsStr::from_bytes(
                         &[
                             old_val.as_os_str().as_bytes(),
@@ -509,13 +1008,13 @@ fn merge_impure_env(mut env: EnvMap) -> EnvMap {
                     ));
                 }
             })
-            .or_insert_with(|| val);
+            .or_insert_with(|| val.clone());
     }
 
     env
 }
 
-fn merge_env(mut env: EnvMap) -> EnvMap {
+fn merge_env(mut env: EnvMap, ambient: &EnvMap, config: &Config) -> EnvMap {
     // These variables are always passed by the original nix-shell, regardless
     // of the --pure flag.
     let keep = &[
@@ -530,9 +1029,13 @@ fn merge_env(mut env: EnvMap) -> EnvMap {
         "PAGER",
         "SHLVL",
     ];
+    let keep = keep
+        .iter()
+        .copied()
+        .chain(config.preserve.iter().map(String::as_str));
     for var in keep {
-        if let Some(vel) = std::env::var_os(var) {
-            env.insert(OsString::from(var), vel);
+        if let Some(vel) = ambient.get(OsStr::new(var)) {
+            env.insert(OsString::from(var), vel.clone());
         }
     }
     env
@@ -545,62 +1048,38 @@ fn build_bash_options(env: &EnvOptions) -> Vec<OsString> {
     const SHELL_OPTIONS: [&[u8]; 1] = [b"pipefail"];
     chain!(
         env.bashopts
-            .as_bytes()
-            .split(|b| *b == b':')
-            .filter(|opt| BASH_OPTIONS.contains(opt))
-            .map(|opt| vec!["-O".into(), OsString::from_vec(opt.to_vec())]),
+            .iter()
+            .filter(|opt| BASH_OPTIONS.contains(&opt.as_bytes()))
+            .map(|opt| vec!["-O".into(), opt.clone()]),
         env.shellopts
-            .as_bytes()
-            .split(|b| *b == b':')
-            .filter(|opt| SHELL_OPTIONS.contains(opt))
-            .map(|opt| vec!["-o".into(), OsString::from_vec(opt.to_vec())]),
+            .iter()
+            .filter(|opt| SHELL_OPTIONS.contains(&opt.as_bytes()))
+            .map(|opt| vec!["-o".into(), opt.clone()]),
     )
     .flatten()
     .collect()
 }
 
-fn check_cache(hash: &str) -> Option<BTreeMap<OsString, OsString>> {
-    let env_fname = XDG_DIRS.find_cache_file(format!("{hash}.env"))?;
-    let drv_fname = XDG_DIRS.find_cache_file(format!("{hash}.drv"))?;
-    let trace_fname = XDG_DIRS.find_cache_file(format!("{hash}.trace"))?;
-
-    let env = read(env_fname).unwrap().pipe(deserealize_env);
+/// Load the entry if it's still valid.  Entries that depend on the network
+/// expire after `ttl`.
+fn check_cache(hash: &str, ttl: Duration) -> Option<Entry> {
+    let entry = Entry::load(hash)?;
 
-    let drv_store_fname = read_link(drv_fname).ok()?;
-    std::fs::metadata(drv_store_fname).ok()?;
+    if !entry.drv.is_empty() {
+        std::fs::metadata(&entry.drv).ok()?;
+    }
 
-    let trace = read(trace_fname).unwrap().pipe(Trace::load);
-    if trace.check_for_changes() {
+    if entry.expired(ttl) {
+        eprintln!("cached-nix-shell: the entry depends on the network and has expired");
         return None;
     }
 
-    Some(env)
-}
-
-fn cache_write(hash: &str, ext: &str, text: &[u8]) {
-    let f = || -> Result<(), std::io::Error> {
-        let fname = XDG_DIRS.place_cache_file(format!("{hash}.{ext}"))?;
-        let mut file = File::create(fname)?;
-        file.write_all(text)?;
-        Ok(())
-    };
-    match f() {
-        Ok(_) => (),
-        Err(e) => eprintln!("Warning: can't store cache: {e}"),
+    if entry.trace.check_for_changes() {
+        return None;
     }
-}
 
-fn cache_symlink(hash: &str, ext: &str, target: &str) {
-    let f = || -> Result<(), std::io::Error> {
-        let fname = XDG_DIRS.place_cache_file(format!("{hash}.{ext}"))?;
-        let _ = std::fs::remove_file(&fname);
-        std::os::unix::fs::symlink(target, &fname)?;
-        Ok(())
-    };
-    match f() {
-        Ok(_) => (),
-        Err(e) => eprintln!("Warning: can't symlink to cache: {e}"),
-    }
+    Entry::touch(hash);
+    Some(entry)
 }
 
 fn wrap(cmd: Vec<OsString>) {
@@ -641,10 +1120,47 @@ fn wrap(cmd: Vec<OsString>) {
 fn main() {
     let argv: Vec<OsString> = std::env::args_os().collect();
 
+    if Path::new(&argv[0]).file_name() == Some(OsStr::new("nix")) {
+        // Our symlink in the `--wrap` path.
+        flake::wrapped_nix(std::env::args_os().skip(1).collect());
+    }
+
     if argv.len() >= 2 && argv[1] == "--wrap" {
         wrap(std::env::args_os().skip(2).collect());
     }
 
+    if argv.len() >= 2 && argv[1] == "--develop" {
+        flake::develop(std::env::args_os().skip(2).collect());
+    }
+
+    if argv.len() >= 2 && argv[1] == "--gc" {
+        gc::gc(std::env::args_os().skip(2).collect());
+    }
+
+    if argv.len() >= 2 && (argv[1] == "--pin" || argv[1] == "--unpin") {
+        gc::pin(std::env::args_os().skip(2).collect(), argv[1] == "--pin");
+    }
+
+    if argv.len() >= 2 && argv[1] == "--refresh" {
+        refresh::refresh(std::env::args_os().skip(2).collect());
+    }
+
+    if argv.len() >= 2 && argv[1] == "--reload" {
+        reload::reload(std::env::args_os().skip(2).collect());
+    }
+
+    if argv.len() >= 2 && argv[1] == "--status" {
+        inspect::status(std::env::args_os().skip(2).collect());
+    }
+
+    if argv.len() >= 2 && argv[1] == "--list" {
+        inspect::list(std::env::args_os().skip(2).collect());
+    }
+
+    if argv.len() >= 2 && argv[1] == "--show" {
+        inspect::show(std::env::args_os().skip(2).collect());
+    }
+
     if argv.len() >= 2 {
         let fname = &argv[1];
         if let Some(nix_shell_args) = shebang::parse_script(fname) {
@@ -0,0 +1,195 @@
+//! Nix version and capabilities
+//!
+//! Nix commands differ between releases: `nix develop` and `nix print-dev-env`
+//! appear in Nix 2.4 behind the `nix-command` experimental feature, `nix
+//! show-derivation` becomes `nix derivation show` in 2.15, etc.  Rather than
+//! guessing by trial and error, the version is probed once with `nix
+//! --version`.  The result is cached in the `nix-version` file in the cache
+//! directory, keyed on the resolved path of the `nix` binary, i.e. on its store
+//! path, so upgrading Nix triggers a new probe.  The enabled experimental
+//! features depend on the configuration rather than on the binary, so they
+//! aren't cached.
+
+use crate::{cache, deserialize_vecs, serialize_vecs};
+use once_cell::sync::Lazy;
+use std::os::unix::ffi::OsStrExt;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use ufcs::Pipe;
+
+const CACHE_FILE: &str = "nix-version";
+
+static INFO: Lazy<NixInfo> = Lazy::new(NixInfo::load);
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Version(pub u32, pub u32, pub u32);
+
+impl Version {
+    /// Parse the output of `nix --version`, e.g. `nix (Nix) 2.18.1`.
+    fn parse(s: &str) -> Option<Version> {
+        let mut parts = s.split_whitespace().last()?.split('.').map(|part| {
+            part.bytes()
+                .take_while(u8::is_ascii_digit)
+                .fold(0u32, |n, d| n.saturating_mul(10) + u32::from(d - b'0'))
+        });
+        Some(Version(
+            parts.next()?,
+            parts.next()?,
+            parts.next().unwrap_or(0),
+        ))
+    }
+}
+
+impl std::fmt::Display for Version {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}.{}.{}", self.0, self.1, self.2)
+    }
+}
+
+pub struct NixInfo {
+    /// Resolved path to the `nix` binary.
+    pub path: PathBuf,
+    /// `None` if `nix --version` failed.
+    pub version: Option<Version>,
+}
+
+/// Information about the Nix used to evaluate shells.
+pub fn get() -> &'static NixInfo {
+    &INFO
+}
+
+impl NixInfo {
+    /// True if the version is at least `major.minor`.  An unknown version is
+    /// assumed to be a recent one.
+    pub fn at_least(&self, major: u32, minor: u32) -> bool {
+        self.version.map_or(true, |v| v >= Version(major, minor, 0))
+    }
+
+    fn load() -> NixInfo {
+        let path = binary();
+        let path = path.canonicalize().unwrap_or(path);
+        if let Some(info) = NixInfo::load_cached(&path) {
+            return info;
+        }
+        let info = NixInfo::probe(path);
+        if info.version.is_some() {
+            let _ = info.save();
+        }
+        info
+    }
+
+    fn load_cached(path: &Path) -> Option<NixInfo> {
+        let data = std::fs::read(cache::find_file(CACHE_FILE)?).ok()?;
+        match deserialize_vecs(&data)?[..] {
+            [cached_path, version]
+                if cached_path == path.as_os_str().as_bytes() =>
+            {
+                Some(NixInfo {
+                    path: path.to_path_buf(),
+                    version: Some(Version::parse(
+                        std::str::from_utf8(version).ok()?,
+                    )?),
+                })
+            }
+            _ => None,
+        }
+    }
+
+    fn save(&self) -> Result<(), std::io::Error> {
+        let version = self.version.map(|v| v.to_string()).unwrap_or_default();
+        let data = serialize_vecs(&[
+            self.path.as_os_str().as_bytes(),
+            version.as_bytes(),
+        ]);
+        std::fs::write(cache::place_file(CACHE_FILE)?, data)
+    }
+
+    fn probe(path: PathBuf) -> NixInfo {
+        let version = Command::new(&path)
+            .arg("--version")
+            .output()
+            .ok()
+            .filter(|output| output.status.success())
+            .and_then(|output| {
+                Version::parse(&String::from_utf8_lossy(&output.stdout))
+            });
+        NixInfo { path, version }
+    }
+
+    /// Experimental features enabled in the current Nix configuration.
+    pub fn features(&self) -> Vec<String> {
+        // Without `nix-command` enabled, `nix show-config` fails on Nix 2.4+.
+        // Enabling it here would hide whether it's enabled in the config.
+        let show_config: &[&str] = match self.version {
+            Some(v) if v >= Version(2, 20, 0) => &["config", "show"],
+            _ => &["show-config"],
+        };
+        Command::new(&self.path)
+            .args(show_config)
+            .arg("--json")
+            .output()
+            .ok()
+            .filter(|output| output.status.success())
+            .and_then(|output| {
+                serde_json::from_slice::<serde_json::Value>(&output.stdout).ok()
+            })
+            .and_then(|json| {
+                json.get("experimental-features")?
+                    .get("value")?
+                    .as_array()?
+                    .iter()
+                    .filter_map(|f| f.as_str().map(String::from))
+                    .collect::<Vec<_>>()
+                    .pipe(Some)
+            })
+            .unwrap_or_default()
+    }
+}
+
+/// The `nix` binary: either the one cached-nix-shell is built with, or the
+/// first one in `$PATH` other than our `--wrap` symlink.
+pub fn binary() -> PathBuf {
+    if !env!("CNS_NIX").is_empty() {
+        return PathBuf::from(concat!(env!("CNS_NIX"), "nix"));
+    }
+    user_binary().unwrap_or_else(|| PathBuf::from("nix"))
+}
+
+/// The first `nix` in `$PATH` other than our `--wrap` symlink.
+pub fn user_binary() -> Option<PathBuf> {
+    std::env::var_os("PATH")
+        .unwrap_or_default()
+        .pipe(|path| std::env::split_paths(&path).collect::<Vec<_>>())
+        .into_iter()
+        .filter(|dir| dir != Path::new(env!("CNS_WRAP_PATH")))
+        .map(|dir| dir.join("nix"))
+        .find(|nix| {
+            nix.is_file()
+                && nix
+                    .canonicalize()
+                    .ok()
+                    .and_then(|x| x.file_name().map(|x| x.to_os_string()))
+                    .map(|x| x != "cached-nix-shell")
+                    .unwrap_or(false)
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_version() {
+        assert_eq!(
+            Version::parse("nix (Nix) 2.18.1\n"),
+            Some(Version(2, 18, 1))
+        );
+        assert_eq!(Version::parse("nix (Nix) 2.3"), Some(Version(2, 3, 0)));
+        assert_eq!(
+            Version::parse("nix (Nix) 2.25.0pre20241010_dirty"),
+            Some(Version(2, 25, 0))
+        );
+        assert_eq!(Version::parse(""), None);
+        assert!(Version(2, 3, 16) < Version(2, 4, 0));
+    }
+}
@@ -0,0 +1,201 @@
+//! Output of the evaluation
+//!
+//! By default, `nix-shell` writes directly to our stdout and stderr while the
+//! cache is updated, so it keeps its progress bar and colors.  In the `replay`
+//! and `hide` modes its output (build logs, messages of `shellHook`, etc.) is
+//! captured instead, and in the `replay` mode stored in the entry, so it can
+//! be replayed on cache hits.
+
+use crate::{deserialize_vecs, serialize_vecs};
+use std::io::{ErrorKind, Read, Write};
+use std::os::unix::io::AsFd;
+use std::process::{Child, ExitStatus, Stdio};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Mutex;
+
+static STDOUT_TO_STDERR: AtomicBool = AtomicBool::new(false);
+
+/// At most this many bytes of the output are captured.
+pub const MAX_SIZE: usize = 1 << 20;
+
+const TRUNCATED: &[u8] =
+    b"cached-nix-shell: the rest of the output is not stored\n";
+
+/// What to do with the output of the evaluation, see `--eval-output`.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Mode {
+    /// Print it during the evaluation.
+    Show,
+    /// Print it during the evaluation and replay it on cache hits.
+    Replay,
+    /// Don't print it unless the evaluation fails.
+    Hide,
+}
+
+impl Mode {
+    pub fn parse(s: &str) -> Result<Mode, String> {
+        match s {
+            "show" => Ok(Mode::Show),
+            "replay" => Ok(Mode::Replay),
+            "hide" => Ok(Mode::Hide),
+            _ => Err(format!(
+                "invalid output mode {s:?}, expected show, replay or hide"
+            )),
+        }
+    }
+
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Mode::Show => "show",
+            Mode::Replay => "replay",
+            Mode::Hide => "hide",
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Stream {
+    Stdout,
+    Stderr,
+}
+
+/// Chunks of the output in the order they were received.
+pub type Output = Vec<(Stream, Vec<u8>)>;
+
+/// Chunks read so far, and whether some were dropped because of [`MAX_SIZE`].
+#[derive(Default)]
+struct Captured {
+    output: Output,
+    size: usize,
+    truncated: bool,
+}
+
+/// Stdout for a child which output isn't captured.
+pub fn inherit_stdout() -> Stdio {
+    if !STDOUT_TO_STDERR.load(Ordering::Relaxed) {
+        return Stdio::inherit();
+    }
+    match std::io::stderr().as_fd().try_clone_to_owned() {
+        Ok(fd) => Stdio::from(fd),
+        Err(_) => Stdio::null(),
+    }
+}
+
+/// Read stdout and stderr of the child until it exits.  Unless `mode` is
+/// [`Mode::Hide`], pass them through to our stdout and stderr.  Only the first
+/// [`MAX_SIZE`] bytes are kept.
+pub fn capture(
+    mut child: Child,
+    mode: Mode,
+) -> Result<(ExitStatus, Output), std::io::Error> {
+    let output = Mutex::new(Captured::default());
+    std::thread::scope(|s| {
+        if let Some(stdout) = child.stdout.take() {
+            s.spawn(|| tee(stdout, Stream::Stdout, mode, &output));
+        }
+        if let Some(stderr) = child.stderr.take() {
+            s.spawn(|| tee(stderr, Stream::Stderr, mode, &output));
+        }
+    });
+    let mut captured = output.into_inner().unwrap();
+    if captured.truncated {
+        captured.output.push((Stream::Stderr, TRUNCATED.to_vec()));
+    }
+    Ok((child.wait()?, captured.output))
+}
+
+fn tee(
+    mut input: impl Read,
+    stream: Stream,
+    mode: Mode,
+    output: &Mutex<Captured>,
+) {
+    let mut buf = [0u8; 8192];
+    loop {
+        let len = match input.read(&mut buf) {
+            Ok(0) => break,
+            Ok(len) => len,
+            Err(e) if e.kind() == ErrorKind::Interrupted => continue,
+            Err(_) => break,
+        };
+        let mut captured = output.lock().unwrap();
+        if mode != Mode::Hide {
+            write(stream, &buf[..len]);
+        }
+        if captured.truncated || captured.size + len > MAX_SIZE {
+            captured.truncated = true;
+            continue;
+        }
+        captured.size += len;
+        captured.output.push((stream, buf[..len].to_vec()));
+    }
+}
+
+/// Print the output to the streams it was originally written to.
+pub fn replay(output: &Output) {
+    for (stream, data) in output {
+        write(*stream, data);
+    }
+}
+
+/// Write everything meant for stdout to stderr instead, so stdout is left for
+/// the result of `--export`.
+pub fn redirect_stdout() {
+    STDOUT_TO_STDERR.store(true, Ordering::Relaxed);
+}
+
+fn write(stream: Stream, data: &[u8]) {
+    let _ = match stream {
+        Stream::Stdout if !STDOUT_TO_STDERR.load(Ordering::Relaxed) => {
+            let mut stdout = std::io::stdout().lock();
+            stdout.write_all(data).and_then(|()| stdout.flush())
+        }
+        _ => std::io::stderr().write_all(data),
+    };
+}
+
+pub fn serialize(output: &Output) -> Vec<u8> {
+    let vecs = output
+        .iter()
+        .flat_map(|(stream, data)| {
+            let tag: &[u8] = match stream {
+                Stream::Stdout => b"stdout",
+                Stream::Stderr => b"stderr",
+            };
+            [tag, data]
+        })
+        .collect::<Vec<_>>();
+    serialize_vecs(&vecs)
+}
+
+/// Inverse of [`serialize`].
+pub fn deserialize(data: &[u8]) -> Option<Output> {
+    let vecs = deserialize_vecs(data)?;
+    if vecs.len() % 2 != 0 {
+        return None;
+    }
+    vecs.chunks(2)
+        .map(|chunk| match chunk[0] {
+            b"stdout" => Some((Stream::Stdout, chunk[1].to_vec())),
+            b"stderr" => Some((Stream::Stderr, chunk[1].to_vec())),
+            _ => None,
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn roundtrip() {
+        let output = vec![
+            (Stream::Stderr, b"building...\n".to_vec()),
+            (Stream::Stdout, b"hello\0world".to_vec()),
+            (Stream::Stderr, Vec::new()),
+        ];
+        assert_eq!(deserialize(&serialize(&output)), Some(output));
+        assert_eq!(deserialize(b""), Some(Vec::new()));
+        assert_eq!(deserialize(b"3\0foo1\0x"), None);
+    }
+}
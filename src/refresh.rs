@@ -0,0 +1,163 @@
+//! `--allow-stale`: reuse an outdated entry and update it in the background
+//!
+//! When the trace check of an entry fails, the command is started right away
+//! with the environment of that entry, and a detached `cached-nix-shell
+//! --refresh HASH` process re-evaluates it.  The new entry atomically replaces
+//! the old one once it's ready, so the next invocation gets the fresh
+//! environment.  The output of the background process goes to `{hash}.log`.
+//!
+//! The entry lock is taken before spawning the refresh process and handed over
+//! to it, so invocations without `--allow-stale` wait for it instead of
+//! evaluating once more, and extra refresh processes exit immediately.
+//! `--ttl` and `--eval-output` in effect are passed to it as well.
+
+use crate::cache::{self, Entry};
+use crate::config::Config;
+use crate::gc::parse_duration;
+use crate::{check_cache, lock, output, update_cache, NixShellInput};
+use nix::libc;
+use nix::unistd::{fork, setsid, ForkResult};
+use std::ffi::OsString;
+use std::fs::File;
+use std::os::unix::process::CommandExt;
+use std::path::PathBuf;
+use std::process::{exit, Command, Stdio};
+
+/// Return the outdated entry and start updating it in the background.  Return
+/// `None` if there is no usable entry.
+pub fn use_stale(
+    hash: &str,
+    inp: &NixShellInput,
+    config: &Config,
+) -> Option<Entry> {
+    let entry = Entry::load(hash)?;
+    // The environment refers to the store paths that are no longer there.
+    if !entry.drv.is_empty() {
+        std::fs::metadata(&entry.drv).ok()?;
+    }
+
+    // Don't restart the update (and truncate its log) if it's still running.
+    let lock = match lock::try_lock_entry(hash) {
+        Some(lock) => lock,
+        None => {
+            eprintln!(
+                "cached-nix-shell: warning: using a stale environment, the cache is being updated in the background"
+            );
+            return Some(entry);
+        }
+    };
+
+    // The lock is released here only if the refresh process failed to start.
+    match spawn_refresh(hash, &inp.weak_args, config, &lock) {
+        Ok(log) => eprintln!(
+            "cached-nix-shell: warning: using a stale environment, updating the cache in the background (log: {})",
+            log.display()
+        ),
+        Err(e) => {
+            eprintln!("cached-nix-shell: warning: can't start a background update: {e}");
+            return None;
+        }
+    }
+    Some(entry)
+}
+
+fn spawn_refresh(
+    hash: &str,
+    weak_args: &[OsString],
+    config: &Config,
+    lock: &lock::EntryLock,
+) -> Result<PathBuf, std::io::Error> {
+    let log = cache::place_file(format!("{hash}.log"))?;
+    let mut cmd = Command::new(std::env::current_exe()?);
+    cmd.arg("--refresh")
+        .arg("--ttl")
+        .arg(config.ttl.as_secs().to_string())
+        .arg("--eval-output")
+        .arg(config.eval_output.as_str())
+        .arg(hash)
+        .args(weak_args)
+        .env(lock::FD_VAR, lock.fd().to_string())
+        .stdin(Stdio::null())
+        .stdout(Stdio::null())
+        .stderr(File::create(&log)?);
+    // Detach from the session so the process survives closing the terminal,
+    // and double-fork so it isn't left as a zombie child of the command we
+    // are about to exec into.
+    let lock_fd = lock.fd();
+    unsafe {
+        cmd.pre_exec(move || {
+            lock::pass_to_child(lock_fd)?;
+            setsid()?;
+            match fork()? {
+                ForkResult::Parent { .. } => libc::_exit(0),
+                ForkResult::Child => Ok(()),
+            }
+        });
+    }
+    cmd.spawn()?.wait()?;
+    Ok(log)
+}
+
+/// `cached-nix-shell --refresh [--ttl SECONDS] [--eval-output MODE] HASH
+/// [WEAK_ARGS]...` (internal)
+pub fn refresh(args: Vec<OsString>) {
+    let mut args = &args[..];
+    let mut ttl = None;
+    let mut eval_output = None;
+    loop {
+        let result = match args {
+            [opt, value, rest @ ..] if opt == "--ttl" => {
+                args = rest;
+                parse_duration(&value.to_string_lossy()).map(|x| ttl = Some(x))
+            }
+            [opt, value, rest @ ..] if opt == "--eval-output" => {
+                args = rest;
+                output::Mode::parse(&value.to_string_lossy())
+                    .map(|x| eval_output = Some(x))
+            }
+            _ => break,
+        };
+        if let Err(e) = result {
+            eprintln!("cached-nix-shell: {e}");
+            exit(1);
+        }
+    }
+    let (hash, weak_args) = match args.split_first() {
+        Some((hash, weak_args)) => (hash.to_string_lossy(), weak_args),
+        None => {
+            eprintln!("cached-nix-shell: entry hash not specified");
+            exit(1);
+        }
+    };
+    let entry = Entry::load(&hash).unwrap_or_else(|| {
+        eprintln!("cached-nix-shell: can't load entry {hash}");
+        exit(1);
+    });
+    let mut inp = entry.decode_inputs().unwrap_or_else(|| {
+        eprintln!("cached-nix-shell: can't decode inputs of {hash}");
+        exit(1);
+    });
+    inp.weak_args = weak_args.to_vec();
+
+    let _lock =
+        match lock::inherit(&hash).or_else(|| lock::try_lock_entry(&hash)) {
+            Some(lock) => lock,
+            None => {
+                eprintln!(
+                    "cached-nix-shell: another process is updating the cache"
+                );
+                exit(0);
+            }
+        };
+    let config = crate::config::load(&inp.pwd);
+    let config = Config {
+        ttl: ttl.unwrap_or(config.ttl),
+        eval_output: eval_output.unwrap_or(config.eval_output),
+        ..config
+    };
+    if check_cache(&hash, config.ttl).is_none() {
+        let ambient = std::env::vars_os().collect();
+        update_cache(&hash, entry.inputs, &inp, &config, &ambient);
+    }
+    exit(0);
+}
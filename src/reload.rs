@@ -0,0 +1,132 @@
+//! `--reload`: update the environment of a running shell
+//!
+//! Every cached shell gets `CACHED_NIX_SHELL_ENTRY` with the hash of its
+//! entry and `CACHED_NIX_SHELL_ENV_HASH` identifying its environment.
+//! Running `eval "$(cached-nix-shell --reload)"` inside the shell checks the
+//! entry against its trace, re-evaluates it if needed, and prints commands
+//! that bring the exported variables of the session up to date: variables
+//! changed by the evaluation are set, the ones it no longer sets are unset.
+//! Other variables, including the ones changed by the user in the session, are
+//! left intact.
+//!
+//! If the entry was already updated after the shell was started (e.g. by
+//! another shell), the environment the shell was started with is unknown.
+//! Then the variables are compared with the session instead, and removed ones
+//! are kept.
+
+use crate::args::Args;
+use crate::bash::{is_identifier, quote};
+use crate::cache::Entry;
+use crate::{config, output, serialize_env, EnvMap, Status};
+use std::ffi::{OsStr, OsString};
+use std::io::Write;
+use std::os::unix::ffi::OsStrExt;
+use std::process::exit;
+
+/// `cached-nix-shell --reload`
+pub fn reload(args: Vec<OsString>) {
+    if !args.is_empty() {
+        eprintln!("cached-nix-shell: --reload doesn't take arguments");
+        exit(1);
+    }
+    let hash = std::env::var("CACHED_NIX_SHELL_ENTRY").unwrap_or_else(|_| {
+        eprintln!("cached-nix-shell: not inside a cached-nix-shell");
+        exit(1);
+    });
+    let old = Entry::load(&hash).unwrap_or_else(|| {
+        eprintln!("cached-nix-shell: entry {hash} is no longer in the cache");
+        exit(1);
+    });
+    let inp = old.decode_inputs().unwrap_or_else(|| {
+        eprintln!("cached-nix-shell: can't decode inputs of {hash}");
+        exit(1);
+    });
+    let config = config::load(&inp.pwd);
+    let session: EnvMap = std::env::vars_os().collect();
+    let same_env = session.get(OsStr::new("CACHED_NIX_SHELL_ENV_HASH"))
+        == Some(&OsString::from(env_hash(&old.env)));
+    if !same_env {
+        eprintln!("cached-nix-shell: warning: the entry was updated after the shell was started, removed variables won't be unset");
+    }
+    // Impure shells always get `impure`, see `merge_impure_env`.
+    let pure = std::env::var_os("IN_NIX_SHELL").map_or(true, |v| v != "impure");
+
+    // Recover the environment the shell was started from, so the new values
+    // are combined with it in the same way as the old ones were: drop the
+    // variables set by the entry, and strip the old values from the ones the
+    // ambient values were appended to.
+    let mut ambient = session.clone();
+    let delimiters = crate::delimiters(&config);
+    for (var, old_val) in &old.env {
+        let val = match session.get(var) {
+            Some(val) => val,
+            None => continue,
+        };
+        let prefix = delimiters
+            .get(var)
+            .map(|delim| [old_val.as_bytes(), delim.as_bytes()].concat());
+        if val == old_val {
+            ambient.remove(var);
+        } else if let Some(val) =
+            prefix.and_then(|prefix| val.as_bytes().strip_prefix(&*prefix))
+        {
+            ambient.insert(var.clone(), OsStr::from_bytes(val).to_os_string());
+        }
+    }
+
+    // The status describes how the shell was started, keep it as is.
+    let status = session
+        .get(OsStr::new("CACHED_NIX_SHELL_STATUS"))
+        .cloned()
+        .unwrap_or_default();
+    let mut old_env = if same_env {
+        crate::shell_env(
+            &old,
+            &inp,
+            &hash,
+            Some(Status::Hit),
+            pure,
+            &ambient,
+            &config,
+        )
+    } else {
+        session
+    };
+    old_env.insert("CACHED_NIX_SHELL_STATUS".into(), status.clone());
+    output::redirect_stdout();
+    let args = Args {
+        pure,
+        ..Args::default()
+    };
+    let mut new_env =
+        crate::cached_shell_env(&args, &config, &inp, &ambient).env;
+    new_env.insert("CACHED_NIX_SHELL_STATUS".into(), status);
+
+    let mut script = Vec::new();
+    for (var, val) in &new_env {
+        if old_env.get(var) != Some(val) && is_identifier(var.as_bytes()) {
+            script.extend(b"export ");
+            script.extend(var.as_bytes());
+            script.push(b'=');
+            script.extend(quote(val.as_bytes()));
+            script.push(b'\n');
+        }
+    }
+    for var in old_env.keys().filter(|_| same_env) {
+        if !new_env.contains_key(var) && is_identifier(var.as_bytes()) {
+            script.extend(b"unset ");
+            script.extend(var.as_bytes());
+            script.push(b'\n');
+        }
+    }
+    if script.is_empty() {
+        eprintln!("cached-nix-shell: the environment is up to date");
+    }
+    let _ = std::io::stdout().write_all(&script);
+    exit(0);
+}
+
+/// A short hash identifying a captured environment.
+pub fn env_hash(env: &EnvMap) -> String {
+    blake3::hash(&serialize_env(env)).to_hex()[..16].to_string()
+}
@@ -1,49 +1,351 @@
+use crate::{git, EnvMap};
 use itertools::Itertools;
-use std::collections::BTreeMap;
+use std::collections::{BTreeMap, BTreeSet};
 use std::ffi::{OsStr, OsString};
-use std::fs::{read, read_dir, read_link, symlink_metadata};
-use std::io::ErrorKind;
-use std::os::unix::ffi::OsStrExt;
+use std::fs::{metadata, read_dir, read_link, symlink_metadata, File};
+use std::io::{ErrorKind, Read};
+use std::num::NonZeroUsize;
+use std::os::unix::ffi::{OsStrExt, OsStringExt};
+use std::os::unix::fs::MetadataExt;
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// Metadata of files modified this close to the start of the evaluation is
+/// not trusted: a later modification within the timestamp granularity of the
+/// file system would leave it unchanged.
+const RACY_MARGIN: Duration = Duration::from_secs(2);
+
+/// Traces smaller than this are checked in the current thread: spawning
+/// threads would take longer than checking.
+const PARALLEL_THRESHOLD: usize = 64;
+
+/// Upper limit on the number of threads used to check a trace.  Checking is
+/// mostly I/O bound, so more threads don't help much.
+const MAX_THREADS: usize = 8;
+
+/// Files are hashed in chunks of this size rather than read at once.
+const HASH_BUF_SIZE: usize = 64 * 1024;
+
+/// Environment variables read by Nix itself rather than by the evaluated
+/// expressions.  Their lookups are not recorded.
+const NIX_VARS: &[&str] = &[
+    "COLORTERM",
+    "COLUMNS",
+    "HOME",
+    "IN_NIX_SHELL",
+    "LANG",
+    "LANGUAGE",
+    "LINES",
+    "LOGNAME",
+    "NO_COLOR",
+    "PAGER",
+    "PATH",
+    "PWD",
+    "SHELL",
+    "TEMP",
+    "TERM",
+    "TMP",
+    "TMPDIR",
+    "TZ",
+    "USER",
+];
+const NIX_VAR_PREFIXES: &[&str] = &[
+    "CLICOLOR", "CURL_", "GC_", "GIT_", "LC_", "NIX_", "SSL_", "XDG_", "_NIX",
+];
+
+/// Header of the trace format: `MAGIC NUL VERSION NUL`, followed by
+/// `TYPE KEY NUL VALUE NUL` records.  Traces without the header are of the
+/// version 1, which has the same records.  Traces of other versions, or with
+/// records of unknown types, are rejected, so an entry written by a newer
+/// cached-nix-shell is a cache miss rather than a misread.
+const MAGIC: &[u8] = b"cached-nix-shell-trace";
+const VERSION: &[u8] = b"2";
+
+/// Known record types:
+/// `s`, `f`, `d`: `lstat`, `open` and `opendir` of a file by trace-nix.so,
+/// `m`: metadata of a file, `e`: an environment variable (only in the output
+/// of trace-nix.so, see [`Trace::resolve_env`]), `g`: a git repository, see
+/// [`crate::git`].
+const RECORD_TYPES: &[u8] = b"sfdmeg";
 
 /// Output of trace-nix.so, sorted and deduplicated.
+#[derive(Default)]
 pub struct Trace {
     items: BTreeMap<Vec<u8>, Vec<u8>>,
+    /// `m` records: file name -> metadata of the file at the time it was
+    /// read.  Used to skip rehashing of unchanged files and directories.
+    meta: BTreeMap<Vec<u8>, Vec<u8>>,
+}
+
+/// Why a trace can't be parsed.
+#[derive(Debug, PartialEq)]
+pub enum LoadError {
+    /// Of an unknown version, or with records of unknown types.
+    Version,
+    /// Truncated, e.g. the writer was killed.
+    Corrupt,
 }
 
 impl Trace {
-    pub fn load(vec: Vec<u8>) -> Trace {
-        let items = vec
+    /// Parse the output of trace-nix.so.  Unlike [`Trace::serialize`], it
+    /// terminates every field with NUL, so truncation is detected reliably.
+    pub fn load_raw(vec: Vec<u8>) -> Result<Trace, LoadError> {
+        if vec.last().is_some_and(|&b| b != 0) {
+            return Err(LoadError::Corrupt);
+        }
+        Trace::load(vec)
+    }
+
+    /// Parse a trace.
+    pub fn load(vec: Vec<u8>) -> Result<Trace, LoadError> {
+        let mut fields = vec
             .split(|&b| b == 0)
             .filter(|&fname| !fname.is_empty()) // last entry has trailing NUL
-            .map(Vec::from)
-            .tuples::<(_, _)>()
-            .collect::<BTreeMap<Vec<u8>, Vec<u8>>>();
-        Trace { items }
+            .collect::<Vec<_>>();
+        if fields.first() == Some(&MAGIC) {
+            if fields.get(1) != Some(&VERSION) {
+                return Err(LoadError::Version);
+            }
+            fields.drain(..2);
+        }
+        if fields.len() % 2 != 0 {
+            return Err(LoadError::Corrupt);
+        }
+
+        let mut trace = Trace::default();
+        for (k, v) in fields.into_iter().tuples() {
+            match k.first() {
+                Some(b'm') => trace.meta.insert(k[1..].to_vec(), v.to_vec()),
+                Some(op) if RECORD_TYPES.contains(op) => {
+                    trace.items.insert(k.to_vec(), v.to_vec())
+                }
+                _ => return Err(LoadError::Version),
+            };
+        }
+        Ok(trace)
     }
 
     pub fn serialize(&self) -> Vec<u8> {
-        let mut result = Vec::<u8>::new();
+        let mut result = [MAGIC, b"\0", VERSION].concat();
         for (a, b) in &self.items {
             result.push(0);
             result.extend(a);
             result.push(0);
             result.extend(b);
         }
+        for (a, b) in &self.meta {
+            result.extend(b"\0m");
+            result.extend(a);
+            result.push(0);
+            result.extend(b);
+        }
         result
     }
 
+    /// Forget metadata of files modified after `start` (minus
+    /// [`RACY_MARGIN`]), so they are always rehashed.
+    pub fn forget_racy_meta(&mut self, start: SystemTime) {
+        let threshold = start
+            .checked_sub(RACY_MARGIN)
+            .unwrap_or(UNIX_EPOCH)
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default();
+        self.meta.retain(|_, v| {
+            let mut fields = v.split(|&b| b == b':').skip(1);
+            let mtime = fields.next().and_then(parse_time);
+            let ctime = fields.next().and_then(parse_time);
+            matches!((mtime, ctime), (Some(m), Some(c)) if m.max(c) < threshold)
+        });
+    }
+
+    /// Drop `e` records of trace-nix.so, i.e. the values of environment
+    /// variables seen by the evaluation.  They can't change without a change
+    /// of the cache key: the variables in `eval_env` are a part of it, and the
+    /// other ones are unset during the evaluation whatever their current
+    /// values are.  Return the names of the latter, except the ones used by
+    /// Nix itself.
+    pub fn resolve_env(&mut self, eval_env: &EnvMap) -> Vec<OsString> {
+        let mut unpassed = Vec::new();
+        self.items.retain(|k, _| match k.strip_prefix(b"e") {
+            Some(name) => {
+                let name = OsStr::from_bytes(name);
+                if !eval_env.contains_key(name) && !is_nix_var(name) {
+                    unpassed.push(name.to_os_string());
+                }
+                false
+            }
+            None => true,
+        });
+        unpassed
+    }
+
+    /// Replace records of files under `.git` directories, except the index,
+    /// with `g` records of the resolved `HEAD` of the repositories, see
+    /// [`crate::git`].
+    pub fn resolve_git(&mut self) {
+        let mut repos = BTreeSet::new();
+        self.items.retain(|k, _| {
+            if !is_file_op(k) {
+                return true;
+            }
+            let path = Path::new(OsStr::from_bytes(&k[1..]));
+            match git::classify(path) {
+                Some(git::GitFile::Head(git_dir)) => {
+                    repos.insert(git_dir);
+                    false
+                }
+                Some(git::GitFile::Index) | None => true,
+                Some(git::GitFile::Other) => false,
+            }
+        });
+        self.meta.retain(|k, _| {
+            let path = Path::new(OsStr::from_bytes(k));
+            matches!(git::classify(path), Some(git::GitFile::Index) | None)
+        });
+        for git_dir in repos {
+            // Older Nix versions read it in `git` subprocesses, which aren't
+            // traced.
+            self.add_file(git_dir.join("index").as_os_str());
+            let key = [b"g", git_dir.as_os_str().as_bytes()].concat();
+            self.items.insert(key, git::head(&git_dir).into_bytes());
+        }
+    }
+
+    /// Record the current content of a file, as if it was read during the
+    /// evaluation.  Records made by trace-nix.so take precedence.
+    pub fn add_file(&mut self, fname: &OsStr) {
+        let value = match hash_file(fname) {
+            Ok(hash) => hash,
+            Err(ref e) if e.kind() == ErrorKind::NotFound => "-".into(),
+            Err(_) => "e".into(),
+        };
+        self.add(b'f', fname, value);
+    }
+
+    /// Record the current listing of a directory, see [`Trace::add_file`].
+    pub fn add_dir(&mut self, fname: &OsStr) {
+        self.add(b'd', fname, hash_dir(fname));
+    }
+
+    fn add(&mut self, op: u8, fname: &OsStr, value: OsString) {
+        let key = [&[op], fname.as_bytes()].concat();
+        if self.items.contains_key(&key) {
+            return;
+        }
+        self.items.insert(key, value.into_vec());
+        if let Some(meta) = current_meta(fname) {
+            self.meta.entry(fname.as_bytes().to_vec()).or_insert(meta);
+        }
+    }
+
+    /// Number of traced items.
+    pub fn len(&self) -> usize {
+        self.items.len()
+    }
+
+    /// Names of the traced files and directories.
+    pub fn files(&self) -> Vec<&OsStr> {
+        self.items
+            .keys()
+            .filter(|k| is_file_op(k))
+            .map(|k| OsStr::from_bytes(&k[1..]))
+            .sorted()
+            .dedup()
+            .collect()
+    }
+
+    /// Human-readable description of traced items, one per line.
+    pub fn describe(&self) -> Vec<String> {
+        self.items
+            .iter()
+            .map(|(k, v)| describe_item(k, v))
+            .collect()
+    }
+
     /// Return true if trace doesn't match (i.e. some file is changed)
     pub fn check_for_changes(&self) -> bool {
-        for (k, v) in &self.items {
-            if check_item_updated(k, v) {
-                return true;
+        match self.check_items(true).into_iter().next() {
+            Some(change) => {
+                eprintln!("cached-nix-shell: {change}");
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Describe every item that doesn't match, rather than only the first.
+    pub fn changes(&self) -> Vec<String> {
+        self.check_items(false)
+    }
+
+    /// Check items using several threads.  If `stop_early`, stop as soon as
+    /// any change is found.  Changes are returned in the order of items.
+    fn check_items(&self, stop_early: bool) -> Vec<String> {
+        let items = self.items.iter().collect::<Vec<_>>();
+        let threads = std::thread::available_parallelism()
+            .map_or(1, NonZeroUsize::get)
+            .min(MAX_THREADS)
+            .min(items.len() / PARALLEL_THRESHOLD);
+
+        if threads <= 1 {
+            let changes = items
+                .iter()
+                .filter_map(|(k, v)| self.check_item_updated(k, v));
+            return if stop_early {
+                changes.take(1).collect()
+            } else {
+                changes.collect()
+            };
+        }
+
+        let next = AtomicUsize::new(0);
+        let stop = AtomicBool::new(false);
+        let changes = Mutex::new(Vec::new());
+        std::thread::scope(|scope| {
+            for _ in 0..threads {
+                scope.spawn(|| {
+                    while !stop.load(Ordering::Relaxed) {
+                        let idx = next.fetch_add(1, Ordering::Relaxed);
+                        let (k, v) = match items.get(idx) {
+                            Some(item) => item,
+                            None => break,
+                        };
+                        if let Some(change) = self.check_item_updated(k, v) {
+                            changes.lock().unwrap().push((idx, change));
+                            if stop_early {
+                                stop.store(true, Ordering::Relaxed);
+                            }
+                        }
+                    }
+                });
+            }
+        });
+        let mut changes = changes.into_inner().unwrap();
+        changes.sort_by_key(|(idx, _)| *idx);
+        if stop_early {
+            changes.truncate(1);
+        }
+        changes.into_iter().map(|(_, change)| change).collect()
+    }
+
+    /// Return a description of the change if the item doesn't match.
+    fn check_item_updated(&self, k: &[u8], v: &[u8]) -> Option<String> {
+        let fname = OsStr::from_bytes(&k[1..]);
+        if let (Some(b'f') | Some(b'd'), Some(meta)) =
+            (k.first(), self.meta.get(&k[1..]))
+        {
+            if current_meta(fname).as_ref() == Some(meta) {
+                // Same metadata as at evaluation time, no need to rehash.
+                return None;
             }
         }
-        false
+        check_item_content(k, v)
     }
 }
 
-fn check_item_updated(k: &[u8], v: &[u8]) -> bool {
+fn check_item_content(k: &[u8], v: &[u8]) -> Option<String> {
     let tmp: OsString;
     let fname = OsStr::from_bytes(&k[1..]);
     let res = match k.iter().next() {
@@ -51,10 +353,16 @@ fn check_item_updated(k: &[u8], v: &[u8]) -> bool {
             Err(_) => OsStr::new("-"),
             Ok(md) => {
                 if md.file_type().is_symlink() {
-                    let mut l = OsString::from("l");
-                    l.push(read_link(fname).expect("Can't read link"));
-                    tmp = l;
-                    tmp.as_os_str()
+                    match read_link(fname) {
+                        Ok(link) => {
+                            let mut l = OsString::from("l");
+                            l.push(link);
+                            tmp = l;
+                            tmp.as_os_str()
+                        }
+                        // Removed in the meantime.
+                        Err(_) => OsStr::new("-"),
+                    }
                 } else if md.file_type().is_dir() {
                     OsStr::new("d")
                 } else {
@@ -62,11 +370,9 @@ fn check_item_updated(k: &[u8], v: &[u8]) -> bool {
                 }
             }
         },
-        Some(b'f') => match read(fname) {
-            Ok(data) => {
-                tmp = OsString::from(
-                    &blake3::hash(&data).to_hex().as_str()[..32],
-                );
+        Some(b'f') => match hash_file(fname) {
+            Ok(hash) => {
+                tmp = hash;
                 tmp.as_os_str()
             }
             Err(ref e) if e.kind() == ErrorKind::NotFound => OsStr::new("-"),
@@ -76,19 +382,95 @@ fn check_item_updated(k: &[u8], v: &[u8]) -> bool {
             tmp = hash_dir(fname);
             tmp.as_os_str()
         }
-        _ => panic!("Unexpected"),
+        Some(b'g') => {
+            tmp = git::head(Path::new(fname)).into();
+            tmp.as_os_str()
+        }
+        _ => return Some(format!("{fname:?}: unknown record")),
     };
 
     if res.as_bytes() != v {
-        eprintln!(
-            "cached-nix-shell: {:?}: expected {:?}, got {:?}",
+        return Some(format!(
+            "{:?}: expected {:?}, got {:?}",
             fname,
             OsStr::from_bytes(v),
             res
-        );
-        return true;
+        ));
     }
-    false
+    None
+}
+
+/// Hash file contents without loading the whole file into memory.
+fn hash_file(fname: &OsStr) -> Result<OsString, std::io::Error> {
+    let mut file = File::open(fname)?;
+    let mut hasher = blake3::Hasher::new();
+    let mut buf = vec![0; HASH_BUF_SIZE];
+    loop {
+        match file.read(&mut buf) {
+            Ok(0) => break,
+            Ok(n) => hasher.update(&buf[..n]),
+            Err(e) if e.kind() == ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e),
+        };
+    }
+    Ok(OsString::from(&hasher.finalize().to_hex().as_str()[..32]))
+}
+
+/// True for records of `lstat`, `open` and `opendir` calls, whose keys are
+/// file names.
+fn is_file_op(k: &[u8]) -> bool {
+    matches!(k.first(), Some(b's' | b'f' | b'd'))
+}
+
+fn is_nix_var(name: &OsStr) -> bool {
+    let name = name.to_string_lossy();
+    NIX_VARS.contains(&name.as_ref())
+        || NIX_VAR_PREFIXES.iter().any(|p| name.starts_with(p))
+        || name.to_ascii_lowercase().ends_with("_proxy")
+}
+
+/// Metadata in the same format as `m` records of trace-nix.so.
+fn current_meta(fname: &OsStr) -> Option<Vec<u8>> {
+    let md = metadata(fname).ok()?;
+    let meta = format!(
+        "{}:{}.{:09}:{}.{:09}:{}:{}",
+        md.size(),
+        md.mtime(),
+        md.mtime_nsec(),
+        md.ctime(),
+        md.ctime_nsec(),
+        md.ino(),
+        md.dev(),
+    );
+    Some(meta.into_bytes())
+}
+
+/// Parse `SECONDS.NANOSECONDS`.
+fn parse_time(s: &[u8]) -> Option<Duration> {
+    let (secs, nanos) = std::str::from_utf8(s).ok()?.split_once('.')?;
+    Some(Duration::new(secs.parse().ok()?, nanos.parse().ok()?))
+}
+
+fn describe_item(k: &[u8], v: &[u8]) -> String {
+    let fname = String::from_utf8_lossy(k.get(1..).unwrap_or_default());
+    let v = String::from_utf8_lossy(v);
+    let (op, state) = match (k.first(), v.as_ref()) {
+        (Some(b's'), "-") => ("lstat", "missing".to_string()),
+        (Some(b's'), "d") => ("lstat", "directory".to_string()),
+        (Some(b's'), "+") => ("lstat", "exists".to_string()),
+        (Some(b's'), l) if l.starts_with('l') => {
+            ("lstat", format!("symlink to {}", &l[1..]))
+        }
+        (Some(b'f'), "-") => ("read", "missing".to_string()),
+        (Some(b'f'), "e") => ("read", "unreadable".to_string()),
+        (Some(b'f'), hash) => ("read", format!("content {hash}")),
+        (Some(b'd'), "-") => ("readdir", "missing".to_string()),
+        (Some(b'd'), hash) => ("readdir", format!("listing {hash}")),
+        (Some(b'g'), "-") => ("git", "not a repository".to_string()),
+        (Some(b'g'), head) => ("git", format!("at {head}")),
+        _ => ("unknown", v.to_string()),
+    };
+    format!("{op:<8}{fname}: {state}")
 }
 
 fn hash_dir(fname: &OsStr) -> OsString {
@@ -123,3 +505,178 @@ fn hash_dir(fname: &OsStr) -> OsString {
         });
     OsString::from(&hasher.finalize().to_hex().as_str()[..32])
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn meta_fast_path() {
+        let dir = tempfile::tempdir().unwrap();
+        let fname = dir.path().join("a");
+        std::fs::write(&fname, "x").unwrap();
+        let fname = fname.as_os_str();
+
+        // The hash is bogus, so the trace only matches if it isn't rechecked.
+        let data = [
+            b"\0f" as &[u8],
+            fname.as_bytes(),
+            b"\0bogus\0m",
+            fname.as_bytes(),
+            b"\0",
+            &current_meta(fname).unwrap(),
+        ]
+        .concat();
+        assert!(Trace::load(data.clone()).unwrap().changes().is_empty());
+        let serialized = Trace::load(data.clone()).unwrap().serialize();
+        assert!(serialized.starts_with(b"cached-nix-shell-trace\x002\0"));
+        assert!(serialized.ends_with(&data));
+
+        let mut trace = Trace::load(data.clone()).unwrap();
+        trace.forget_racy_meta(SystemTime::now());
+        assert_eq!(trace.changes().len(), 1);
+
+        std::fs::write(fname, "yy").unwrap();
+        assert_eq!(Trace::load(data).unwrap().changes().len(), 1);
+    }
+
+    #[test]
+    fn versions() {
+        let v1 = b"\0s/foo\0+\0f/bar\0-\0m/bar\0meta".to_vec();
+        let v2 = Trace::load(v1.clone()).unwrap().serialize();
+        assert_eq!(Trace::load(v2.clone()).unwrap().serialize(), v2);
+        assert_eq!(Trace::load(v2).unwrap().len(), 2);
+
+        // The header as written by trace-nix.so.
+        let so = b"cached-nix-shell-trace\x002\0s/foo\0+\0".to_vec();
+        assert_eq!(Trace::load_raw(so.clone()).unwrap().len(), 1);
+        let truncated = so[..so.len() - 1].to_vec();
+        assert_eq!(Trace::load_raw(truncated).err(), Some(LoadError::Corrupt));
+
+        let v3 = [b"cached-nix-shell-trace\x003\0" as &[u8], &v1].concat();
+        assert_eq!(Trace::load(v3).err(), Some(LoadError::Version));
+        let unknown = b"\0x/foo\0+".to_vec();
+        assert_eq!(Trace::load(unknown).err(), Some(LoadError::Version));
+        let odd = b"\0s/foo".to_vec();
+        assert_eq!(Trace::load(odd).err(), Some(LoadError::Corrupt));
+    }
+
+    #[test]
+    fn parallel_check() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut data = Vec::new();
+        for i in 0..500 {
+            let fname = dir.path().join(format!("{i:03}"));
+            if i % 100 != 7 {
+                std::fs::write(&fname, "").unwrap();
+            }
+            data.extend(b"\0s");
+            data.extend(fname.as_os_str().as_bytes());
+            data.extend(b"\0+");
+        }
+        let trace = Trace::load(data).unwrap();
+        let changes = trace.changes();
+        assert_eq!(changes.len(), 5);
+        assert!(changes[0].contains("/007\""));
+        assert!(changes[4].contains("/407\""));
+        assert!(trace.check_for_changes());
+    }
+
+    #[test]
+    fn add_records() {
+        let dir = tempfile::tempdir().unwrap();
+        let fname = dir.path().join("a");
+        std::fs::write(&fname, "x").unwrap();
+
+        let mut trace = Trace::load(Vec::new()).unwrap();
+        trace.add_file(fname.as_os_str());
+        trace.add_file(dir.path().join("missing").as_os_str());
+        trace.add_dir(dir.path().as_os_str());
+        assert_eq!(trace.len(), 3);
+        assert!(!trace.check_for_changes());
+
+        // Records of trace-nix.so are kept as is.
+        let mut data = b"\0f".to_vec();
+        data.extend(fname.as_os_str().as_bytes());
+        data.extend(b"\0-");
+        let mut trace = Trace::load(data).unwrap();
+        trace.add_file(fname.as_os_str());
+        assert!(trace.check_for_changes());
+
+        std::fs::write(dir.path().join("b"), "").unwrap();
+        let mut trace = Trace::load(Vec::new()).unwrap();
+        trace.add_dir(dir.path().as_os_str());
+        std::fs::remove_file(dir.path().join("b")).unwrap();
+        assert!(trace.check_for_changes());
+    }
+
+    #[test]
+    fn env_records() {
+        let data = b"\0eCNS_TRACE_TEST_SET\0-\0eCNS_TRACE_TEST_UNSET\0-\0eCNS_TRACE_TEST_KEPT\0=x\0eNIX_PATH\0-";
+        let mut eval_env = EnvMap::new();
+        eval_env.insert("CNS_TRACE_TEST_KEPT".into(), "x".into());
+
+        let mut trace = Trace::load(data.to_vec()).unwrap();
+        assert_eq!(
+            trace.resolve_env(&eval_env),
+            ["CNS_TRACE_TEST_SET", "CNS_TRACE_TEST_UNSET"],
+        );
+        assert_eq!(trace.len(), 0);
+    }
+
+    #[test]
+    fn git_records() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path().as_os_str().as_bytes();
+        let git = dir.path().join(".git");
+        std::fs::create_dir_all(git.join("objects")).unwrap();
+        std::fs::write(git.join("HEAD"), "ref: refs/heads/main\n").unwrap();
+        std::fs::write(git.join("index"), "").unwrap();
+        let index = hash_file(git.join("index").as_os_str()).unwrap();
+        let wt_git = git.join("worktrees/wt");
+        std::fs::create_dir_all(&wt_git).unwrap();
+        std::fs::write(wt_git.join("HEAD"), "ref: refs/heads/wt\n").unwrap();
+        std::fs::write(wt_git.join("commondir"), "../..\n").unwrap();
+
+        let mut data = Vec::new();
+        for (op, name, value) in [
+            (b's', "/.git", "d"),
+            (b'f', "/.git/HEAD", "bogus"),
+            (b'f', "/.git/index", index.to_str().unwrap()),
+            (b'm', "/.git/index", "bogus"),
+            (b'f', "/.git/refs/heads/wt", "-"),
+            (b'f', "/.git/worktrees/wt/HEAD", "bogus"),
+        ] {
+            data.extend(
+                [b"\0", &[op] as &[u8], root, name.as_bytes()].concat(),
+            );
+            data.extend([b"\0", value.as_bytes()].concat());
+        }
+        let mut trace = Trace::load(data).unwrap();
+        trace.resolve_git();
+        // `g` records and indexes of the repository and of the work tree.
+        assert_eq!(trace.len(), 4);
+        assert_eq!(trace.meta.len(), 1);
+        assert!(!trace.check_for_changes());
+
+        std::fs::write(wt_git.join("HEAD"), "ref: refs/heads/other\n").unwrap();
+        assert!(trace.check_for_changes());
+        std::fs::write(wt_git.join("HEAD"), "ref: refs/heads/wt\n").unwrap();
+
+        std::fs::write(git.join("HEAD"), "ref: refs/heads/other\n").unwrap();
+        assert!(trace.check_for_changes());
+        std::fs::write(git.join("HEAD"), "ref: refs/heads/main\n").unwrap();
+        assert!(!trace.check_for_changes());
+
+        // `git add`
+        std::fs::write(git.join("index"), "changed").unwrap();
+        assert!(trace.check_for_changes());
+    }
+
+    #[test]
+    fn test_parse_time() {
+        assert_eq!(parse_time(b"12.000000345"), Some(Duration::new(12, 345)));
+        assert_eq!(parse_time(b"12"), None);
+        assert_eq!(parse_time(b"-1.0"), None);
+    }
+}
@@ -1,6 +1,7 @@
 set -e
 
 export XDG_CACHE_HOME=$PWD/tmp/cache
+export XDG_CONFIG_HOME=$PWD/tmp/config
 rm -rf ./tmp
 mkdir -p ./tmp
 
@@ -13,7 +14,7 @@ at_exit() {
 	local rc=$(($? || result))
 	local end_t
 	end_t=$(date +%s)
-	set -- tmp/cache/cached-nix-shell/*.env
+	set -- tmp/cache/cached-nix-shell/*.entry
 	[ -f "$1" ] || shift
 	printf "\33[1m* rc:%s seconds:%s entries:%s\33[m\n" \
 		"$rc" "$((end_t - begin_t))" "$#"
@@ -0,0 +1,22 @@
+#!/bin/sh
+. ./lib.sh
+# Concurrent invocations with the same inputs should evaluate only once.
+
+put ./tmp/shell.nix << 'EOF'
+with import <nixpkgs> { }; mkShell { shellHook = "sleep 3"; VAR = "val"; }
+EOF
+
+for i in 1 2 3; do
+	cached-nix-shell ./tmp/shell.nix --run 'echo $VAR' \
+		> tmp/out$i 2> tmp/err$i &
+done
+wait
+
+cat tmp/out1 tmp/out2 tmp/out3 > tmp/out
+cat tmp/err1 tmp/err2 tmp/err3 > tmp/err
+
+check "all commands got the env" \
+	test "$(grep -c '^val$' tmp/out)" = 3
+check "evaluated once" \
+	test "$(grep -c '^cached-nix-shell: updating cache$' tmp/err)" = 1
+check_stderr_contains "waiting for another process"
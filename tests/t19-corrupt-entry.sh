@@ -0,0 +1,25 @@
+#!/bin/sh
+. ./lib.sh
+# Damaged cache entries should be treated as absent.
+
+put ./tmp/shell.nix << 'EOF'
+with import <nixpkgs> { }; mkShell { VAR = "val"; }
+EOF
+
+run cached-nix-shell ./tmp/shell.nix --run 'echo $VAR'
+check_contains '^val$'
+check_slow
+
+for f in tmp/cache/cached-nix-shell/*.entry; do
+	head -c 100 "$f" > tmp/truncated
+	cp tmp/truncated "$f"
+done
+
+run cached-nix-shell ./tmp/shell.nix --run 'echo $VAR'
+check_contains '^val$'
+check_stderr_contains "ignoring corrupt cache entry"
+check_slow
+
+run cached-nix-shell ./tmp/shell.nix --run 'echo $VAR'
+check_contains '^val$'
+check_fast
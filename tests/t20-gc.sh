@@ -0,0 +1,41 @@
+#!/bin/sh
+. ./lib.sh
+# Test --gc
+
+mkdir -p tmp/a tmp/b
+for d in a b; do
+	put ./tmp/$d/shell.nix << 'EOF'
+with import <nixpkgs> { }; mkShell { }
+EOF
+done
+
+run cached-nix-shell ./tmp/a/shell.nix --run :
+run cached-nix-shell ./tmp/b/shell.nix --run :
+count() {
+	set -- tmp/cache/cached-nix-shell/*.entry
+	[ -f "$1" ] || shift
+	echo "$#"
+}
+check "two entries" test "$(count)" = 2
+
+run cached-nix-shell --gc
+check "live entries are kept" test "$(count)" = 2
+
+rm -rf tmp/b
+run cached-nix-shell --gc --dry-run
+check_contains "would remove"
+check "dry run keeps entries" test "$(count)" = 2
+
+run cached-nix-shell --gc
+check_contains "no longer exists"
+check "entry with deleted directory is removed" test "$(count)" = 1
+
+touch -d '@0' tmp/cache/cached-nix-shell/*.entry
+run cached-nix-shell ./tmp/a/shell.nix --run :
+check_fast
+run cached-nix-shell --gc --max-age 1d
+check "recently used entry is kept" test "$(count)" = 1
+
+run cached-nix-shell --gc --max-size 1
+check_contains "cache size limit exceeded"
+check "size limit is enforced" test "$(count)" = 0
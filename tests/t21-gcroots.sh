@@ -0,0 +1,39 @@
+#!/bin/sh
+. ./lib.sh
+# Test GC roots, --pin and --unpin
+
+put ./tmp/shell.nix << 'EOF'
+with import <nixpkgs> { }; mkShell { buildInputs = [ lua ]; }
+EOF
+
+run cached-nix-shell ./tmp/shell.nix --run 'command -v lua'
+check_slow
+
+lua_path=$(cat tmp/out)
+check "lua is rooted" \
+	grep -qF "${lua_path%/bin/lua}" tmp/cache/cached-nix-shell/gcroots/*/*
+check "lua is rooted in nix" \
+	sh -c 'nix-store --gc --print-roots | grep -q "cached-nix-shell.*-lua"'
+
+# Missing roots are restored on a cache hit.
+rm -rf tmp/cache/cached-nix-shell/gcroots
+run cached-nix-shell ./tmp/shell.nix --run 'command -v lua'
+check_fast
+check "roots are restored" \
+	grep -qF "${lua_path%/bin/lua}" tmp/cache/cached-nix-shell/gcroots/*/*
+
+hash=$(basename tmp/cache/cached-nix-shell/*.entry .entry)
+run cached-nix-shell --pin "$(echo "$hash" | head -c 8)"
+check_contains "^pinned $hash$"
+
+run cached-nix-shell --gc --max-age 0
+check "pinned entry is kept" test -f "tmp/cache/cached-nix-shell/$hash.entry"
+
+run cached-nix-shell --unpin "$hash"
+check_contains "^unpinned $hash$"
+
+run cached-nix-shell --gc --max-age 0
+check "unpinned entry is removed" \
+	not test -f "tmp/cache/cached-nix-shell/$hash.entry"
+check "roots are released" \
+	not test -e "tmp/cache/cached-nix-shell/gcroots/$hash"
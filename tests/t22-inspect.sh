@@ -0,0 +1,34 @@
+#!/bin/sh
+. ./lib.sh
+# Test --list and --show
+
+put ./tmp/shell.nix << 'EOF'
+with import <nixpkgs> { };
+mkShell { VAR = builtins.readFile ./var.txt; }
+EOF
+echo hello > tmp/var.txt
+
+run cached-nix-shell ./tmp/shell.nix --argstr foo bar --run 'echo $VAR'
+check_slow
+
+run cached-nix-shell --list
+check_contains "$PWD/tmp"
+check_contains "argstr foo bar"
+
+hash=$(basename tmp/cache/cached-nix-shell/*.entry .entry)
+run cached-nix-shell --show "$(echo "$hash" | head -c 8)"
+check_contains "^hash: *$hash$"
+check_contains "^derivation: */nix/store/.*\.drv$"
+check_contains "^pwd: *$PWD/tmp$"
+
+run cached-nix-shell --show "$hash" --env
+check_contains "^VAR='hello$"
+
+run cached-nix-shell --show "$hash" --trace
+check_contains "^read *$PWD/tmp/var.txt: content "
+
+# Unrelated files are ignored.
+: > tmp/cache/cached-nix-shell/x.entry
+run cached-nix-shell --list
+check_contains "$PWD/tmp"
+check "x.entry is not listed" not grep -q "^x " tmp/out
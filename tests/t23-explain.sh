@@ -0,0 +1,26 @@
+#!/bin/sh
+. ./lib.sh
+# Test --explain
+
+put ./tmp/shell.nix << 'EOF'
+with import <nixpkgs> { };
+mkShell { A = builtins.readFile ./a.txt; B = builtins.readFile ./b.txt; }
+EOF
+echo a > tmp/a.txt
+echo b > tmp/b.txt
+
+run cached-nix-shell ./tmp/shell.nix --run :
+check_slow
+
+export http_proxy=http://localhost:1
+run cached-nix-shell ./tmp/shell.nix --explain --run :
+check_slow
+check_stderr_contains "explain: no entry for these inputs"
+check_stderr_contains 'explain:   $http_proxy: (unset) -> "http://localhost:1"'
+
+echo aa > tmp/a.txt
+echo bb > tmp/b.txt
+run cached-nix-shell ./tmp/shell.nix --explain --run :
+check_slow
+check_stderr_contains "explain: .*/tmp/a.txt"
+check_stderr_contains "explain: .*/tmp/b.txt"
@@ -0,0 +1,24 @@
+#!/bin/sh
+. ./lib.sh
+# Test --allow-stale
+
+put ./tmp/shell.nix << 'EOF'
+with import <nixpkgs> { };
+mkShell { VAR = builtins.readFile ./var.txt; }
+EOF
+echo old > tmp/var.txt
+
+run cached-nix-shell ./tmp/shell.nix --allow-stale --run 'echo $VAR'
+check_contains '^old$'
+check_slow
+
+echo new > tmp/var.txt
+run cached-nix-shell ./tmp/shell.nix --allow-stale --run 'echo $VAR'
+check_contains '^old$'
+check_fast
+check_stderr_contains "using a stale environment"
+
+# Waits for the background update instead of evaluating once more.
+run cached-nix-shell ./tmp/shell.nix --run 'echo $VAR'
+check_contains '^new$'
+check_fast
@@ -0,0 +1,37 @@
+#!/bin/sh
+. ./lib.sh
+# Test global and per-project configuration files
+
+mkdir -p tmp/config/cached-nix-shell
+put ./tmp/config/cached-nix-shell/config.toml << 'EOF'
+[env]
+keep = ["GLOBAL_VAR"]
+EOF
+
+put ./tmp/cached-nix-shell.toml << 'EOF'
+[env]
+keep = ["PROJECT_VAR"]
+preserve = ["PRESERVED_VAR"]
+EOF
+
+put ./tmp/shell.nix << 'EOF'
+with import <nixpkgs> { };
+mkShell {
+  A = builtins.getEnv "GLOBAL_VAR";
+  B = builtins.getEnv "PROJECT_VAR";
+}
+EOF
+
+export GLOBAL_VAR=global PROJECT_VAR=project PRESERVED_VAR=preserved
+run cached-nix-shell ./tmp/shell.nix --pure --run 'echo "$A|$B|$PRESERVED_VAR"'
+check_contains '^global||preserved$'
+check_stderr_contains 'env.keep is only allowed in the global config'
+check_slow
+
+run env PRESERVED_VAR=changed cached-nix-shell ./tmp/shell.nix --pure --run 'echo "$A|$B|$PRESERVED_VAR"'
+check_contains '^global||changed$'
+check_fast
+
+run env GLOBAL_VAR=changed cached-nix-shell ./tmp/shell.nix --pure --run 'echo "$A|$B|$PRESERVED_VAR"'
+check_contains '^changed||preserved$'
+check_slow
@@ -0,0 +1,27 @@
+#!/bin/sh
+. ./lib.sh
+# Check that non-exported variables and functions are preserved.
+
+put ./tmp/shell.nix << 'EOF'
+with import <nixpkgs> { };
+mkShell {
+  shellHook = ''
+    foo() { echo foo-called; }
+    bar=baz
+    declare -A assoc=([key]=value)
+  '';
+}
+EOF
+
+run cached-nix-shell ./tmp/shell.nix --run 'foo; echo $bar ${assoc[key]}'
+check_contains '^foo-called$'
+check_contains '^baz value$'
+check_slow
+
+run cached-nix-shell ./tmp/shell.nix --run 'foo; echo $bar ${assoc[key]}'
+check_contains '^foo-called$'
+check_contains '^baz value$'
+check_fast
+
+run cached-nix-shell ./tmp/shell.nix --run 'echo "[$__CNS_STATE]"'
+check_contains '^\[\]$'
@@ -0,0 +1,32 @@
+#!/bin/sh
+. ./lib.sh
+# Check that --rerun-shell-hook runs shellHook on cache hits.
+
+put ./tmp/shell.nix << 'EOF'
+with import <nixpkgs> { };
+mkShell { shellHook = "echo hook-ran; hookvar=set"; }
+EOF
+
+run cached-nix-shell ./tmp/shell.nix --rerun-shell-hook --run 'echo "[$hookvar]"'
+check_contains '^hook-ran$'
+check_contains '^\[set\]$'
+check_slow
+
+run cached-nix-shell ./tmp/shell.nix --rerun-shell-hook --run 'echo "[$hookvar]"'
+check_contains '^hook-ran$'
+check_contains '^\[set\]$'
+check_fast
+
+run cached-nix-shell ./tmp/shell.nix --run 'echo "[$hookvar]"'
+check "does not contain hook-ran" \
+	not grep -q '^hook-ran$' tmp/out
+check_fast
+
+put ./tmp/cached-nix-shell.toml << 'EOF'
+[shell]
+rerun-hook = true
+EOF
+
+run cached-nix-shell ./tmp/shell.nix --run 'echo "[$hookvar]"'
+check_contains '^hook-ran$'
+check_fast
@@ -0,0 +1,51 @@
+#!/bin/sh
+. ./lib.sh
+# Check that the output of the evaluation is replayed or hidden.
+
+put ./tmp/shell.nix << 'EOF'
+with import <nixpkgs> { };
+mkShell { shellHook = "echo remember-migrations"; }
+EOF
+
+run cached-nix-shell ./tmp/shell.nix --run :
+check_contains '^remember-migrations$'
+check_slow
+
+run cached-nix-shell ./tmp/shell.nix --run :
+check "does not contain remember-migrations" \
+	not grep -q '^remember-migrations$' tmp/out
+check_fast
+
+# Only the replay mode stores the output.
+run cached-nix-shell ./tmp/shell.nix --eval-output replay --run :
+check "does not contain remember-migrations" \
+	not grep -q '^remember-migrations$' tmp/out
+check_fast
+
+put ./tmp/shell.nix << 'EOF'
+with import <nixpkgs> { };
+mkShell { shellHook = "echo remember-migrations-2"; }
+EOF
+
+run cached-nix-shell ./tmp/shell.nix --eval-output hide --run :
+check "does not contain remember-migrations-2" \
+	not grep -q '^remember-migrations-2$' tmp/out
+check_slow
+
+put ./tmp/shell.nix << 'EOF'
+with import <nixpkgs> { };
+mkShell { shellHook = "echo remember-migrations-3"; }
+EOF
+
+put ./tmp/cached-nix-shell.toml << 'EOF'
+[shell]
+eval-output = "replay"
+EOF
+
+run cached-nix-shell ./tmp/shell.nix --run :
+check_contains '^remember-migrations-3$'
+check_slow
+
+run cached-nix-shell ./tmp/shell.nix --run :
+check_contains '^remember-migrations-3$'
+check_fast
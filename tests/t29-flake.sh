@@ -0,0 +1,48 @@
+#!/bin/sh
+. ./lib.sh
+# Test --develop and nix develop under --wrap
+
+if ! nix --extra-experimental-features 'nix-command flakes' develop --help \
+	> /dev/null 2>&1; then
+	skip "nix develop is not supported"
+	exit 0
+fi
+
+mkdir -p ./tmp/flake
+put ./tmp/flake/flake.nix << 'EOF'
+{
+  outputs = { self }: {
+    devShells.${builtins.currentSystem}.default =
+      with import <nixpkgs> { };
+      mkShell { FOO = import ./foo.nix; };
+  };
+}
+EOF
+echo '"foo"' > ./tmp/flake/foo.nix
+echo 'unused' > ./tmp/flake/unused
+git -C ./tmp/flake init -q
+git -C ./tmp/flake add .
+
+run cached-nix-shell --develop ./tmp/flake --impure -c sh -c 'echo "[$FOO]"'
+check_contains '^\[foo\]$'
+check_slow
+
+run cached-nix-shell --develop ./tmp/flake --impure -c sh -c 'echo "[$FOO]"'
+check_contains '^\[foo\]$'
+check_fast
+
+run --chdir tmp/flake cached-nix-shell --wrap \
+	nix develop --impure -c sh -c 'echo "[$FOO]"'
+check_contains '^\[foo\]$'
+check_fast
+
+echo '"bar"' > ./tmp/flake/foo.nix
+run cached-nix-shell --develop ./tmp/flake --impure -c sh -c 'echo "[$FOO]"'
+check_contains '^\[bar\]$'
+check_slow
+
+# The whole flake is copied into the store, so any file might matter.
+echo 'changed' > ./tmp/flake/unused
+run cached-nix-shell --develop ./tmp/flake --impure -c sh -c 'echo "[$FOO]"'
+check_contains '^\[bar\]$'
+check_slow
@@ -0,0 +1,59 @@
+#!/bin/sh
+. ./lib.sh
+# Test --backend print-dev-env
+
+if ! nix --extra-experimental-features 'nix-command flakes' print-dev-env \
+	--help > /dev/null 2>&1; then
+	skip "nix print-dev-env is not supported"
+	exit 0
+fi
+
+put ./tmp/shell.nix << 'EOF'
+with import <nixpkgs> { };
+mkShell {
+  FOO = "foo";
+  shellHook = ''
+    echo hook-ran
+    hello() { echo "hello $1"; }
+  '';
+}
+EOF
+
+run cached-nix-shell ./tmp/shell.nix --backend print-dev-env \
+	--run 'echo "[$FOO] [$IN_NIX_SHELL]"; hello world'
+check_contains '^hook-ran$'
+check_contains '^\[foo\] \[impure\]$'
+check_contains '^hello world$'
+check_slow
+
+run cached-nix-shell ./tmp/shell.nix --backend print-dev-env \
+	--run 'echo "[$FOO] [$IN_NIX_SHELL]"; hello world'
+check_contains '^hook-ran$'
+check_contains '^\[foo\] \[impure\]$'
+check_contains '^hello world$'
+check_fast
+
+run cached-nix-shell ./tmp/shell.nix --backend print-dev-env --pure \
+	--run 'echo "[$FOO] [$IN_NIX_SHELL]"'
+check_contains '^\[foo\] \[pure\]$'
+
+run cached-nix-shell -p --backend print-dev-env --run 'echo ok'
+check_contains '^ok$'
+check_stderr_contains 'falling back to run'
+
+put ./tmp/keep.nix << 'EOF'
+with import <nixpkgs> { };
+mkShell { BAR = builtins.getEnv "CNS_T30"; }
+EOF
+
+export CNS_T30=a
+run cached-nix-shell ./tmp/keep.nix --backend print-dev-env --keep CNS_T30 \
+	--pure --run 'echo "[$BAR] [$CNS_T30]"'
+check_contains '^\[a\] \[a\]$'
+check_slow
+
+export CNS_T30=b
+run cached-nix-shell ./tmp/keep.nix --backend print-dev-env --keep CNS_T30 \
+	--pure --run 'echo "[$BAR] [$CNS_T30]"'
+check_contains '^\[b\] \[b\]$'
+check_slow
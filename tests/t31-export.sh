@@ -0,0 +1,45 @@
+#!/bin/sh
+. ./lib.sh
+# Test --export
+
+put ./tmp/shell.nix << 'EOF'
+with import <nixpkgs> { };
+mkShell {
+  FOO = "a \"b\" $c";
+  shellHook = "echo hook-output";
+}
+EOF
+
+run cached-nix-shell ./tmp/shell.nix --pure --export json
+check "starts with {" grep -q '^{' tmp/out
+check "does not contain hook-output" \
+	not grep -q 'hook-output' tmp/out
+check_contains '^  "FOO": "a \\"b\\" \$c",$'
+check_slow
+cp tmp/out tmp/out-evaluated
+
+run cached-nix-shell ./tmp/shell.nix --pure --export json
+check "same as after the evaluation" cmp -s tmp/out tmp/out-evaluated
+check_fast
+
+run cached-nix-shell ./tmp/shell.nix --pure --export dotenv
+check_contains '^FOO="a \\"b\\" \\\$c"$'
+check_fast
+
+run cached-nix-shell ./tmp/shell.nix --pure --export systemd
+check_contains '^FOO="a \\"b\\" \\\$c"$'
+check_fast
+
+# Non-UTF-8 values
+export VAR="a$(printf '%b' '\360')b"
+run cached-nix-shell ./tmp/shell.nix --pure --keep VAR --export env0
+check "contains VAR" sh -c "tr '\\0' '\\n' < tmp/out | cat -v | grep -q '^VAR=aM-pb$'"
+
+run cached-nix-shell ./tmp/shell.nix --pure --keep VAR --export json
+check "contains VAR bytes" grep -q '^    "bytes": \[$' tmp/out
+unset VAR
+
+run cached-nix-shell ./tmp/shell.nix --pure --export direnv
+check_contains "^export FOO='a \"b\" \$c'$"
+check_contains "^watch_file '.*/tmp/shell.nix'$"
+check_fast
@@ -0,0 +1,26 @@
+#!/bin/sh
+. ./lib.sh
+# Test --reload
+
+put ./tmp/shell.nix << 'EOF'
+with import <nixpkgs> { };
+mkShell { FOO = "old"; GONE = "1"; }
+EOF
+
+put ./tmp/new.nix << 'EOF'
+with import <nixpkgs> { };
+mkShell { FOO = "new"; BAR = "bar"; }
+EOF
+
+run cached-nix-shell ./tmp/shell.nix --run '
+	export MINE=mine
+	cp ./tmp/new.nix ./tmp/shell.nix
+	eval "$(cached-nix-shell --reload)"
+	echo "[$FOO] [$BAR] [${GONE-unset}] [$MINE]"
+	cached-nix-shell --reload
+'
+check_contains '^\[new\] \[bar\] \[unset\] \[mine\]$'
+check_stderr_contains 'the environment is up to date'
+
+run cached-nix-shell --reload
+check_stderr_contains 'not inside a cached-nix-shell'
@@ -0,0 +1,39 @@
+#!/bin/sh
+. ./lib.sh
+# Test CACHED_NIX_SHELL_* variables and --status
+
+put ./tmp/shell.nix << 'EOF'
+with import <nixpkgs> { };
+mkShell { FOO = "old"; }
+EOF
+
+run cached-nix-shell ./tmp/shell.nix --run '
+	echo "status=$CACHED_NIX_SHELL_STATUS"
+	echo "args=$CACHED_NIX_SHELL_ARGS"
+	cached-nix-shell --status
+'
+check_contains '^status=evaluated$'
+check_contains '^args=.*tmp/shell.nix$'
+check_contains '^state: *up to date$'
+
+put ./tmp/new.nix << 'EOF'
+with import <nixpkgs> { };
+mkShell { FOO = "new"; }
+EOF
+
+run cached-nix-shell ./tmp/shell.nix --run '
+	echo "status=$CACHED_NIX_SHELL_STATUS"
+	echo "pwd=$CACHED_NIX_SHELL_PWD"
+	echo "drv=$CACHED_NIX_SHELL_DRV"
+	cp ./tmp/new.nix ./tmp/shell.nix
+	cached-nix-shell --status || echo "exit=$?"
+'
+check_contains '^status=hit$'
+check_contains "^pwd=$PWD/tmp\$"
+check_contains '^drv=/nix/store/.*\.drv$'
+check_contains '^state: *outdated$'
+check_contains 'shell.nix'
+check_contains '^exit=1$'
+
+run cached-nix-shell --status
+check_stderr_contains 'not inside a cached-nix-shell'
@@ -0,0 +1,29 @@
+#!/bin/sh
+. ./lib.sh
+# Test tracing of builtins.getEnv
+
+put ./tmp/shell.nix << 'EOF'
+with import <nixpkgs> { };
+mkShell { FOO = builtins.getEnv "CNS_T34"; }
+EOF
+
+unset CNS_T34
+run cached-nix-shell ./tmp/shell.nix --run 'echo "[$FOO]"'
+check_contains '^\[\]$'
+check "no warning" not grep -q 'use --keep' tmp/err
+check_slow
+
+run cached-nix-shell ./tmp/shell.nix --run 'echo "[$FOO]"'
+check_fast
+
+# The variable isn't passed, so it can't change the result.
+export CNS_T34=value
+run cached-nix-shell ./tmp/shell.nix --run 'echo "[$FOO]"'
+check_contains '^\[\]$'
+check_stderr_contains 'use --keep CNS_T34'
+check_fast
+
+run cached-nix-shell ./tmp/shell.nix --keep CNS_T34 --run 'echo "[$FOO]"'
+check_contains '^\[value\]$'
+check "no warning" not grep -q 'use --keep' tmp/err
+check_slow
@@ -0,0 +1,41 @@
+#!/bin/sh
+. ./lib.sh
+# Test expiration of entries that depend on the network
+
+echo one > tmp/data
+put ./tmp/shell.nix << EOF
+with import <nixpkgs> { };
+mkShell { DATA = builtins.readFile (builtins.fetchurl "file://$PWD/tmp/data"); }
+EOF
+
+run cached-nix-shell ./tmp/shell.nix --ttl 3 --run 'echo "[$DATA]"'
+check_contains '^\[one\]$'
+check_slow
+
+run cached-nix-shell ./tmp/shell.nix --ttl 3 --run 'echo "[$DATA]"'
+check_fast
+
+sleep 4
+run cached-nix-shell ./tmp/shell.nix --ttl 3 --run 'echo "[$DATA]"'
+check_stderr_contains 'has expired'
+check_slow
+
+# The TTL in effect is used, not the one of the evaluation.
+run cached-nix-shell ./tmp/shell.nix --ttl 1h --run 'echo "[$DATA]"'
+check_fast
+sleep 2
+run cached-nix-shell ./tmp/shell.nix --ttl 1 --run 'echo "[$DATA]"'
+check_stderr_contains 'has expired'
+check_slow
+
+# Entries that don't depend on the network never expire.
+put ./tmp/local.nix << 'EOF'
+with import <nixpkgs> { };
+mkShell { }
+EOF
+
+run cached-nix-shell ./tmp/local.nix --ttl 1 --run :
+check_slow
+sleep 2
+run cached-nix-shell ./tmp/local.nix --ttl 1 --run :
+check_fast
@@ -0,0 +1,50 @@
+#!/bin/sh
+. ./lib.sh
+# Test tracking of local git repositories used by fetchGit
+
+git() {
+	command git -C tmp/repo -c user.name=test -c user.email=test@example.com "$@"
+}
+
+mkdir tmp/repo
+git init -q
+echo one > tmp/repo/data
+put tmp/repo/shell.nix << 'EOF'
+with import <nixpkgs> { };
+mkShell { DATA = builtins.readFile "${builtins.fetchGit ./.}/data"; }
+EOF
+git add data shell.nix
+git commit -qm one
+
+run cached-nix-shell ./tmp/repo/shell.nix --run 'echo "$DATA"'
+check_contains '^one$'
+check_slow
+
+# Unrelated changes under .git don't invalidate the cache.
+git gc -q
+run cached-nix-shell ./tmp/repo/shell.nix --run 'echo "$DATA"'
+check_fast
+
+# Staged files are a part of a dirty tree.
+echo new > tmp/repo/new
+git add new
+run cached-nix-shell ./tmp/repo/shell.nix --run 'echo "$DATA"'
+check_slow
+
+echo two > tmp/repo/data
+git commit -qam two
+run cached-nix-shell ./tmp/repo/shell.nix --run 'echo "$DATA"'
+check_contains '^two$'
+check_slow
+
+# Work trees are tracked by their own HEAD, not the one of the main repository.
+git worktree add -q ../wt
+run cached-nix-shell ./tmp/wt/shell.nix --run 'echo "$DATA"'
+check_contains '^two$'
+check_slow
+
+echo three > tmp/wt/data
+git -C ../wt commit -qam three
+run cached-nix-shell ./tmp/wt/shell.nix --run 'echo "$DATA"'
+check_contains '^three$'
+check_slow